@@ -6,7 +6,11 @@ PROJECT_ROOT_DIR=$(realpath "$(cd -- "$(dirname -- "${BASH_SOURCE[0]}")" &>/dev/
 cd "$PROJECT_ROOT_DIR"
 
 echo "Installing service..."
-sudo cp -r "$PROJECT_ROOT_DIR/lambda/updateDatabase/service/dodgetracker.service" /etc/systemd/system/
+sed -e "s|@PROJECT_ROOT_DIR@|$PROJECT_ROOT_DIR|g" \
+    -e "s|@BACKEND_DIR@|$PROJECT_ROOT_DIR/src/backend|g" \
+    "$PROJECT_ROOT_DIR/src/backend/dodgetracker.service" |
+    sudo tee /etc/systemd/system/dodgetracker.service >/dev/null
+sudo systemctl daemon-reload
 echo "Service installed."
 
 cd - >/dev/null
@@ -1,5 +1,6 @@
 import { db } from "@/src/db";
 import { apexTierPlayers, riotIds, summoners } from "@/src/db/schema";
+import { searchName } from "@/src/lib/utils";
 import { and, eq, like, or } from "drizzle-orm";
 import { distance } from "fastest-levenshtein";
 import { type NextRequest } from "next/server";
@@ -86,8 +87,8 @@ export async function GET(request: NextRequest) {
       or(
         and(
           eq(summoners.region, region.toUpperCase()),
-          like(riotIds.lowerGameName, gameName.toLowerCase() + "%"),
-          like(riotIds.lowerTagLine, tagLine.toLowerCase() + "%"),
+          like(riotIds.lowerGameName, searchName(gameName) + "%"),
+          like(riotIds.lowerTagLine, searchName(tagLine) + "%"),
         ),
         like(riotIds.lolprosSlug, gameName + "%"),
       ),
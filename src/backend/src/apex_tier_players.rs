@@ -1,21 +1,30 @@
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
 use riven::consts::{PlatformRoute, QueueType};
-use riven::models::league_v4::LeagueItem;
+use riven::models::league_v4::{LeagueItem, LeagueList};
 use sea_orm::sea_query::OnConflict;
 use sea_orm::{ActiveValue, ColumnTrait, DatabaseTransaction, EntityTrait, QueryFilter};
-use tokio::try_join;
+use tokio::join;
 use tracing::info;
 use tracing::instrument;
 
-use crate::config::INSERT_CHUNK_SIZE;
+use crate::config;
 use crate::entities::apex_tier_players;
 use crate::entities::sea_orm_active_enums::RankTierEnum;
-use crate::riot_api::RIOT_API;
+use crate::riot_api;
+use crate::snapshots;
 use crate::util::with_timeout;
 
+/// Players on the apex tier ladder keyed by summoner ID, along with their tier.
+pub type ApiPlayers = HashMap<String, (LeagueItem, RankTierEnum)>;
+
+/// Amount of players in master, grandmaster and challenger, in that order.
+pub type TierCounts = (usize, usize, usize);
+
 #[instrument(skip_all)]
 pub async fn get_players_from_db(
     txn: &DatabaseTransaction,
@@ -43,42 +52,57 @@ pub async fn get_players_from_db(
     Ok(result)
 }
 
-#[instrument(name = "apex_api", skip(region))]
+/// Where the master, grandmaster and challenger ladders of a region are fetched from.
+#[async_trait]
+pub trait LadderSource: Send + Sync {
+    async fn fetch(&self, region: PlatformRoute) -> Result<(LeagueList, LeagueList, LeagueList)>;
+}
+
+/// Fetches the ladders from the Riot API.
+pub struct RiotLadder;
+
+#[async_trait]
+impl LadderSource for RiotLadder {
+    async fn fetch(&self, region: PlatformRoute) -> Result<(LeagueList, LeagueList, LeagueList)> {
+        let timeout = config::get().timeouts.league();
+        let riot_api = riot_api::for_region(region);
+        let master = with_timeout(
+            timeout,
+            riot_api
+                .league_v4()
+                .get_master_league(region, QueueType::RANKED_SOLO_5x5),
+        );
+        let grandmaster = with_timeout(
+            timeout,
+            riot_api
+                .league_v4()
+                .get_grandmaster_league(region, QueueType::RANKED_SOLO_5x5),
+        );
+        let challenger = with_timeout(
+            timeout,
+            riot_api
+                .league_v4()
+                .get_challenger_league(region, QueueType::RANKED_SOLO_5x5),
+        );
+
+        let (master_result, grandmaster_result, challenger_result) =
+            join!(master, grandmaster, challenger);
+        riot_api.record([&master_result, &grandmaster_result, &challenger_result]);
+
+        Ok((master_result??, grandmaster_result??, challenger_result??))
+    }
+}
+
+#[instrument(name = "apex_api", skip(source, region))]
 pub async fn get_players_from_api(
+    source: &dyn LadderSource,
     region: PlatformRoute,
-) -> Result<(
-    HashMap<String, (LeagueItem, RankTierEnum)>,
-    (usize, usize, usize),
-)> {
+) -> Result<(ApiPlayers, TierCounts)> {
     let t1 = Instant::now();
 
-    let timeout = Duration::from_secs(10);
-    let master = with_timeout(
-        timeout,
-        RIOT_API
-            .league_v4()
-            .get_master_league(region, QueueType::RANKED_SOLO_5x5),
-    );
-    let grandmaster = with_timeout(
-        timeout,
-        RIOT_API
-            .league_v4()
-            .get_grandmaster_league(region, QueueType::RANKED_SOLO_5x5),
-    );
-    let challenger = with_timeout(
-        timeout,
-        RIOT_API
-            .league_v4()
-            .get_challenger_league(region, QueueType::RANKED_SOLO_5x5),
-    );
-
     info!("Getting apex tier players from API...");
 
-    let (master_result, grandmaster_result, challenger_result) =
-        try_join!(master, grandmaster, challenger)?;
-    let master_result = master_result?;
-    let grandmaster_result = grandmaster_result?;
-    let challenger_result = challenger_result?;
+    let (master_result, grandmaster_result, challenger_result) = source.fetch(region).await?;
 
     info!(
         perf = t1.elapsed().as_millis(),
@@ -86,26 +110,51 @@ pub async fn get_players_from_api(
         "Apex tier API queries finished.",
     );
 
-    let t2 = Instant::now();
+    let snapshots = &config::get().snapshots;
+    if snapshots.enabled {
+        snapshots::record(
+            snapshots,
+            region,
+            &master_result,
+            &grandmaster_result,
+            &challenger_result,
+        );
+    }
+
+    Ok(from_leagues(
+        master_result,
+        grandmaster_result,
+        challenger_result,
+    ))
+}
+
+/// Keys the players of the master, grandmaster and challenger ladders by summoner ID.
+#[instrument(skip_all)]
+pub fn from_leagues(
+    master: LeagueList,
+    grandmaster: LeagueList,
+    challenger: LeagueList,
+) -> (ApiPlayers, TierCounts) {
+    let t1 = Instant::now();
 
     let (master_count, grandmaster_count, challenger_count) = (
-        master_result.entries.len(),
-        grandmaster_result.entries.len(),
-        challenger_result.entries.len(),
+        master.entries.len(),
+        grandmaster.entries.len(),
+        challenger.entries.len(),
     );
 
-    let result: HashMap<String, (LeagueItem, RankTierEnum)> = master_result
+    let result: ApiPlayers = master
         .entries
         .into_iter()
         .map(|entry| (entry.summoner_id.clone(), (entry, RankTierEnum::Master)))
-        .chain(grandmaster_result.entries.into_iter().map(|entry| {
+        .chain(grandmaster.entries.into_iter().map(|entry| {
             (
                 entry.summoner_id.clone(),
                 (entry, RankTierEnum::Grandmaster),
             )
         }))
         .chain(
-            challenger_result
+            challenger
                 .entries
                 .into_iter()
                 .map(|entry| (entry.summoner_id.clone(), (entry, RankTierEnum::Challenger))),
@@ -113,13 +162,44 @@ pub async fn get_players_from_api(
         .collect();
 
     info!(
-        perf = t2.elapsed().as_millis(),
+        perf = t1.elapsed().as_millis(),
         players = result.len(),
         metric = "apex_api_processed",
         "Apex tier API results processed."
     );
 
-    Ok((result, (master_count, grandmaster_count, challenger_count)))
+    (result, (master_count, grandmaster_count, challenger_count))
+}
+
+/// The stored ladder as it would look after upserting `players` at `now`, for pipelines whose
+/// cycles are rolled back and for replays. Like the stored ladder, it keeps the players that have
+/// left the apex tiers.
+pub fn as_stored(
+    players: &ApiPlayers,
+    previous: &HashMap<String, apex_tier_players::Model>,
+    region: PlatformRoute,
+    now: DateTime<FixedOffset>,
+) -> HashMap<String, apex_tier_players::Model> {
+    let mut stored = previous.clone();
+
+    for (summoner_id, (player, tier)) in players {
+        let previous = previous.get(summoner_id);
+        let model = apex_tier_players::Model {
+            summoner_id: summoner_id.clone(),
+            summoner_name: previous.and_then(|p| p.summoner_name.clone()),
+            region: region.to_string(),
+            current_lp: player.league_points as i64,
+            created_at: previous.map(|p| p.created_at).unwrap_or(now),
+            updated_at: now,
+            wins: player.wins as i64,
+            losses: player.losses as i64,
+            rank_tier: tier.to_owned(),
+            refresh_attempted_at: previous.and_then(|p| p.refresh_attempted_at),
+        };
+        stored.insert(summoner_id.clone(), model);
+    }
+
+    stored
 }
 
 #[instrument(skip_all, fields(players = players.len()))]
@@ -143,7 +223,7 @@ pub async fn upsert_players(
         })
         .collect();
 
-    for chunk in player_models.chunks(INSERT_CHUNK_SIZE) {
+    for chunk in player_models.chunks(config::get().database.insert_chunk_size) {
         apex_tier_players::Entity::insert_many(chunk.to_vec())
             .on_conflict(
                 OnConflict::columns([
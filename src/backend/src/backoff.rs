@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Capped exponential backoff with jitter. Every consecutive failure doubles the upper bound of the
+/// delay until `max` is reached, and the actual delay is picked at random between `base` and that
+/// bound so that regions failing at the same time don't retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Upper bound of the delay for the current attempt.
+    fn ceiling(&self) -> Duration {
+        self.base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max)
+    }
+
+    /// Returns how long to wait before the next attempt and advances the backoff.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+
+        let jittered = rand::thread_rng().gen_range(self.base.as_millis()..=ceiling.as_millis());
+        Duration::from_millis(jittered as u64)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_stay_within_bounds() {
+        let base = Duration::from_millis(100);
+        let max = Duration::from_secs(2);
+        let mut backoff = Backoff::new(base, max);
+
+        for _ in 0..50 {
+            let delay = backoff.next_delay();
+            assert!(delay >= base && delay <= max);
+        }
+    }
+
+    #[test]
+    fn ceiling_grows_exponentially() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(60));
+
+        assert_eq!(backoff.ceiling(), Duration::from_millis(100));
+        backoff.next_delay();
+        assert_eq!(backoff.ceiling(), Duration::from_millis(200));
+        backoff.next_delay();
+        assert_eq!(backoff.ceiling(), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn reset_restarts_from_base() {
+        let base = Duration::from_millis(100);
+        let mut backoff = Backoff::new(base, Duration::from_secs(60));
+
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+
+        assert_eq!(backoff.ceiling(), base);
+    }
+}
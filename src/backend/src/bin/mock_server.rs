@@ -0,0 +1,297 @@
+//! A mock of the Riot API and lolpros endpoints the backend uses, serving fixture data so that the
+//! backend can run offline. Point `riot.base_url` at `http://<listen>/{}` and `lolpros.base_url` at
+//! `http://<listen>/lolpros`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+const LADDERS: [(&str, &str); 3] = [
+    ("challengerleagues", "challenger.json"),
+    ("grandmasterleagues", "grandmaster.json"),
+    ("masterleagues", "master.json"),
+];
+const LOLPROS_FIXTURE: &str = "lolpros.json";
+
+/// Riot tag line of every mocked account. Game names are the summoner IDs.
+const TAG_LINE: &str = "MOCK";
+
+#[derive(Parser, Debug)]
+#[command(about = "Serves fixture data in place of the Riot API and lolpros")]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+    /// Directory with fixtures replacing the bundled ones, using the same file names.
+    #[arg(long)]
+    fixtures: Option<PathBuf>,
+    /// Riot API key that is accepted. Riot requests with another key are answered with 401.
+    #[arg(long)]
+    api_key: Option<String>,
+}
+
+struct Fixtures {
+    /// Ladders keyed by the league-v4 endpoint serving them.
+    ladders: HashMap<&'static str, Value>,
+    /// lolpros profiles keyed by `<game name>#<tag line>`.
+    lolpros: HashMap<String, Value>,
+}
+
+fn bundled(name: &str) -> &'static str {
+    match name {
+        "challenger.json" => include_str!("../../fixtures/mock/challenger.json"),
+        "grandmaster.json" => include_str!("../../fixtures/mock/grandmaster.json"),
+        "master.json" => include_str!("../../fixtures/mock/master.json"),
+        LOLPROS_FIXTURE => include_str!("../../fixtures/mock/lolpros.json"),
+        _ => unreachable!("Unknown fixture {}", name),
+    }
+}
+
+fn read_fixture(dir: Option<&Path>, name: &str) -> Result<Value> {
+    let contents = match dir.map(|dir| dir.join(name)) {
+        Some(path) if path.exists() => {
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?
+        }
+        _ => bundled(name).to_string(),
+    };
+    serde_json::from_str(&contents).with_context(|| format!("Fixture {} is not valid JSON", name))
+}
+
+impl Fixtures {
+    fn load(dir: Option<&Path>) -> Result<Self> {
+        let mut ladders = HashMap::new();
+        for (endpoint, name) in LADDERS {
+            ladders.insert(endpoint, read_fixture(dir, name)?);
+        }
+        let lolpros = serde_json::from_value(read_fixture(dir, LOLPROS_FIXTURE)?)
+            .context("lolpros fixture must be an object of profiles")?;
+
+        Ok(Self { ladders, lolpros })
+    }
+
+    /// The lolpros profiles ordered by slug, with the Riot IDs they are keyed by as their accounts.
+    fn ladder(&self) -> Vec<Value> {
+        let mut profiles: BTreeMap<String, Value> = BTreeMap::new();
+        for (riot_id, profile) in &self.lolpros {
+            let Some(slug) = profile["slug"].as_str() else {
+                continue;
+            };
+            let (game_name, tag_line) = riot_id.rsplit_once('#').unwrap_or((riot_id, ""));
+            let profile = profiles
+                .entry(slug.to_string())
+                .or_insert_with(|| profile.clone());
+            let accounts = profile["league_player"]["accounts"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            profile["league_player"]["accounts"] = json!(accounts
+                .into_iter()
+                .chain([json!({ "game_name": game_name, "tag_line": tag_line })])
+                .collect::<Vec<_>>());
+        }
+        profiles.into_values().collect()
+    }
+
+    /// Answers a GET request with a status code and a JSON body.
+    fn respond(&self, target: &str) -> (u16, Value) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        // Riot paths are prefixed with the route that riven puts in place of `{}`
+        match segments.as_slice() {
+            ["lolpros", "es", "search"] => {
+                let query = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("query="))
+                    .and_then(|query| urlencoding::decode(query).ok())
+                    .unwrap_or_default();
+                let profiles: Vec<&Value> = self.lolpros.get(query.as_ref()).into_iter().collect();
+                (200, json!(profiles))
+            }
+            ["lolpros", "es", "ladder"] => {
+                let param = |name: &str| {
+                    query
+                        .split('&')
+                        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                        .and_then(|value| value.parse::<usize>().ok())
+                };
+                let (page, per_page) =
+                    (param("page").unwrap_or(1), param("per_page").unwrap_or(50));
+                let ladder: Vec<Value> = self
+                    .ladder()
+                    .into_iter()
+                    .skip(page.saturating_sub(1) * per_page)
+                    .take(per_page)
+                    .collect();
+                (200, json!(ladder))
+            }
+            [_, "lol", "league", "v4", endpoint, "by-queue", _] => match self.ladders.get(endpoint)
+            {
+                Some(ladder) => (200, ladder.clone()),
+                None => not_found(),
+            },
+            [_, "lol", "summoner", "v4", "summoners", summoner_id] => (
+                200,
+                json!({
+                    "id": summoner_id,
+                    "accountId": format!("account-{}", summoner_id),
+                    "puuid": format!("puuid-{}", summoner_id),
+                    "profileIconId": 1,
+                    "revisionDate": 0,
+                    "summonerLevel": 30,
+                }),
+            ),
+            [_, "riot", "account", "v1", "accounts", "by-puuid", puuid] => (
+                200,
+                json!({
+                    "puuid": puuid,
+                    "gameName": puuid.strip_prefix("puuid-").unwrap_or(puuid),
+                    "tagLine": TAG_LINE,
+                }),
+            ),
+            [route, "lol", "status", "v4", "platform-data"] => (
+                200,
+                json!({
+                    "id": route.to_uppercase(),
+                    "name": route.to_uppercase(),
+                    "locales": ["en_US"],
+                    "maintenances": [],
+                    "incidents": [],
+                }),
+            ),
+            _ => not_found(),
+        }
+    }
+}
+
+fn not_found() -> (u16, Value) {
+    (
+        404,
+        json!({ "status": { "message": "Not found", "status_code": 404 } }),
+    )
+}
+
+async fn handle(stream: TcpStream, fixtures: &Fixtures, api_key: Option<&str>) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // Requests are GETs without a body, so only the API key header is of interest
+    let mut riot_token = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("x-riot-token") {
+                riot_token = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target))
+            if !target.starts_with("/lolpros/")
+                && api_key.is_some_and(|key| riot_token.as_deref() != Some(key)) =>
+        {
+            (
+                401,
+                json!({ "status": { "message": "Unknown apikey", "status_code": 401 } }),
+            )
+        }
+        (Some("GET"), Some(target)) => fixtures.respond(target),
+        _ => (
+            405,
+            json!({ "status": { "message": "Method not allowed" } }),
+        ),
+    };
+    println!("{} {}", status, request_line.trim());
+
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+
+    let mut stream = reader.into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let fixtures = Arc::new(Fixtures::load(args.fixtures.as_deref())?);
+    let api_key: Option<Arc<str>> = args.api_key.map(Into::into);
+
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    println!("Mock server listening on http://{}", args.listen);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let fixtures = fixtures.clone();
+        let api_key = api_key.clone();
+        tokio::spawn(async move {
+            if let Err(error) = handle(stream, &fixtures, api_key.as_deref()).await {
+                eprintln!("Failed to handle request: {:#}", error);
+            }
+        });
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_bundled_fixtures() {
+        let fixtures = Fixtures::load(None).unwrap();
+
+        let (status, ladder) =
+            fixtures.respond("/euw1/lol/league/v4/challengerleagues/by-queue/RANKED_SOLO_5x5");
+        assert_eq!(status, 200);
+        assert_eq!(ladder["tier"], "CHALLENGER");
+
+        let (status, account) =
+            fixtures.respond("/europe/riot/account/v1/accounts/by-puuid/puuid-mock-summoner-1");
+        assert_eq!(status, 200);
+        assert_eq!(account["gameName"], "mock-summoner-1");
+
+        let (_, profiles) = fixtures.respond("/lolpros/es/search?query=mock-summoner-1%23MOCK");
+        assert_eq!(profiles[0]["slug"], "mock-pro");
+
+        let (_, ladder) = fixtures.respond("/lolpros/es/ladder?page=1&per_page=10");
+        assert_eq!(
+            ladder[0]["league_player"]["accounts"][0]["game_name"],
+            "mock-summoner-1"
+        );
+        let (_, ladder) = fixtures.respond("/lolpros/es/ladder?page=2&per_page=10");
+        assert_eq!(ladder, json!([]));
+
+        assert_eq!(fixtures.respond("/euw1/lol/unknown").0, 404);
+    }
+}
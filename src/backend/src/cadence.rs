@@ -0,0 +1,256 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use chrono::{FixedOffset, Timelike, Utc};
+use riven::consts::PlatformRoute;
+use riven::models::league_v4::LeagueItem;
+use tracing::info;
+
+use crate::config;
+use crate::entities::apex_tier_players;
+use crate::entities::sea_orm_active_enums::RankTierEnum;
+
+/// Amount of recent cycles that are taken into account when estimating activity.
+const ACTIVITY_WINDOW: usize = 10;
+
+/// Amount of games played per cycle at which a region is considered to be at full activity.
+const GAMES_SATURATION: f64 = 40.0;
+
+/// How many games a single dodge is worth when estimating activity. Dodges mean that players are
+/// queueing, so they are a strong signal that the next cycles will be busy.
+const DODGE_WEIGHT: f64 = 5.0;
+
+/// How much the local time of day weighs into the activity estimate, the rest is observed activity.
+const TIME_OF_DAY_WEIGHT: f64 = 0.3;
+
+/// Fraction of the rate limit budget after which the interval is pushed towards the maximum.
+const BUDGET_PRESSURE_THRESHOLD: f64 = 0.75;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PollLimits {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+/// Limits of regions that aren't configured, which only happens when a region is run on its own.
+const DEFAULT_LIMITS: PollLimits = PollLimits {
+    min: Duration::from_millis(7000),
+    max: Duration::from_millis(7000),
+};
+
+/// Approximate UTC offset of the region's player base in hours, used to estimate how busy the
+/// ladder is at the current time of day.
+fn utc_offset_hours(region: PlatformRoute) -> i32 {
+    match region {
+        PlatformRoute::EUW1 => 1,
+        PlatformRoute::EUN1 => 2,
+        PlatformRoute::NA1 => -5,
+        PlatformRoute::KR => 9,
+        PlatformRoute::OC1 => 10,
+        _ => 0,
+    }
+}
+
+/// Expected activity for a local hour of the day, between 0 (dead of night) and 1 (evening peak).
+fn time_of_day_activity(local_hour: u32) -> f64 {
+    match local_hour {
+        0..=1 => 0.6,
+        2..=7 => 0.1,
+        8..=11 => 0.3,
+        12..=16 => 0.6,
+        17..=23 => 1.0,
+        _ => 0.5,
+    }
+}
+
+/// Counts the games that have been played on the ladder since the players were last stored in the
+/// DB. Players that are new to the ladder are not counted since their previous state is unknown.
+pub fn games_played_since(
+    db_players: &HashMap<String, apex_tier_players::Model>,
+    api_players: &HashMap<String, (LeagueItem, RankTierEnum)>,
+) -> u64 {
+    api_players
+        .values()
+        .filter_map(|(new_data, _)| {
+            db_players.get(&new_data.summoner_id).map(|old_data| {
+                let old_games_played = old_data.wins + old_data.losses;
+                let new_games_played = (new_data.wins + new_data.losses) as i64;
+                (new_games_played - old_games_played).max(0) as u64
+            })
+        })
+        .sum()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CycleActivity {
+    games: u64,
+    dodges: usize,
+}
+
+/// Chooses the poll interval of a region based on the activity of its recent cycles, the local
+/// time of day and how much of the rate limit budget has been used.
+#[derive(Debug)]
+pub struct Cadence {
+    region: PlatformRoute,
+    limits: PollLimits,
+    recent: VecDeque<CycleActivity>,
+    requests: VecDeque<(Instant, u32)>,
+}
+
+impl Cadence {
+    pub fn new(region: PlatformRoute) -> Self {
+        Self {
+            region,
+            limits: config::get()
+                .region(region)
+                .map(|config| PollLimits {
+                    min: config.min_interval(),
+                    max: config.max_interval(),
+                })
+                .unwrap_or(DEFAULT_LIMITS),
+            recent: VecDeque::with_capacity(ACTIVITY_WINDOW),
+            requests: VecDeque::new(),
+        }
+    }
+
+    /// Records the outcome of a finished cycle.
+    pub fn record_cycle(&mut self, games: u64, dodges: usize) {
+        if self.recent.len() == ACTIVITY_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(CycleActivity { games, dodges });
+    }
+
+    /// Records API requests made against the region's rate limit.
+    pub fn record_requests(&mut self, requests: u32) {
+        self.requests.push_back((Instant::now(), requests));
+    }
+
+    fn observed_activity(&self) -> f64 {
+        if self.recent.is_empty() {
+            return 1.0;
+        }
+
+        let weighted: f64 = self
+            .recent
+            .iter()
+            .map(|cycle| cycle.games as f64 + cycle.dodges as f64 * DODGE_WEIGHT)
+            .sum();
+        let average = weighted / self.recent.len() as f64;
+
+        (average / GAMES_SATURATION).clamp(0.0, 1.0)
+    }
+
+    fn budget_used(&mut self) -> f64 {
+        let now = Instant::now();
+        let riot = &config::get().riot;
+        while let Some((at, _)) = self.requests.front() {
+            if now.duration_since(*at) > riot.rate_limit_window() {
+                self.requests.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let used: u32 = self.requests.iter().map(|(_, requests)| requests).sum();
+        used as f64 / riot.rate_limit_requests as f64
+    }
+
+    fn local_hour(&self) -> u32 {
+        let offset = FixedOffset::east_opt(utc_offset_hours(self.region) * 3600)
+            .expect("Region UTC offset out of range");
+        Utc::now().with_timezone(&offset).hour()
+    }
+
+    /// Chooses the interval to wait until the next cycle starts.
+    pub fn next_interval(&mut self) -> Duration {
+        let observed = self.observed_activity();
+        let time_of_day = time_of_day_activity(self.local_hour());
+        let budget_used = self.budget_used();
+
+        let interval = choose_interval(self.limits, observed, time_of_day, budget_used);
+
+        info!(
+            interval = interval.as_millis(),
+            observed_activity = observed,
+            time_of_day_activity = time_of_day,
+            budget_used,
+            metric = "poll_cadence",
+            "Chose poll interval."
+        );
+
+        interval
+    }
+}
+
+fn choose_interval(
+    limits: PollLimits,
+    observed_activity: f64,
+    time_of_day_activity: f64,
+    budget_used: f64,
+) -> Duration {
+    let activity =
+        (1.0 - TIME_OF_DAY_WEIGHT) * observed_activity + TIME_OF_DAY_WEIGHT * time_of_day_activity;
+    let span = limits.max.saturating_sub(limits.min);
+
+    let mut interval = limits
+        .max
+        .saturating_sub(span.mul_f64(activity.clamp(0.0, 1.0)));
+
+    if budget_used > BUDGET_PRESSURE_THRESHOLD {
+        let pressure = ((budget_used - BUDGET_PRESSURE_THRESHOLD)
+            / (1.0 - BUDGET_PRESSURE_THRESHOLD))
+            .clamp(0.0, 1.0);
+        interval = interval.max(limits.min + span.mul_f64(pressure));
+    }
+
+    interval.clamp(limits.min, limits.max)
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: PollLimits = PollLimits {
+        min: Duration::from_secs(4),
+        max: Duration::from_secs(20),
+    };
+
+    #[test]
+    fn busy_region_polls_at_min_interval() {
+        assert_eq!(choose_interval(LIMITS, 1.0, 1.0, 0.0), LIMITS.min);
+    }
+
+    #[test]
+    fn idle_region_polls_at_max_interval() {
+        assert_eq!(choose_interval(LIMITS, 0.0, 0.0, 0.0), LIMITS.max);
+    }
+
+    #[test]
+    fn exhausted_budget_polls_at_max_interval() {
+        assert_eq!(choose_interval(LIMITS, 1.0, 1.0, 1.0), LIMITS.max);
+    }
+
+    #[test]
+    fn interval_stays_within_limits() {
+        for activity in [-1.0, 0.0, 0.3, 0.7, 1.0, 2.0] {
+            for budget_used in [0.0, 0.8, 5.0] {
+                let interval = choose_interval(LIMITS, activity, activity, budget_used);
+                assert!(interval >= LIMITS.min && interval <= LIMITS.max);
+            }
+        }
+    }
+
+    #[test]
+    fn dodges_raise_activity() {
+        let mut quiet = Cadence::new(PlatformRoute::EUW1);
+        let mut busy = Cadence::new(PlatformRoute::EUW1);
+        quiet.record_cycle(5, 0);
+        busy.record_cycle(5, 3);
+
+        assert!(busy.observed_activity() > quiet.observed_activity());
+    }
+}
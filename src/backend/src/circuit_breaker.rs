@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use riven::consts::PlatformRoute;
+use strum_macros::EnumIter;
+use tracing::{info, warn};
+
+use crate::backoff::Backoff;
+
+/// External services that a region update depends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter)]
+pub enum Dependency {
+    /// Platform routed Riot endpoints, e.g. league-v4 and summoner-v4.
+    RiotPlatform,
+    /// Regionally routed Riot endpoints, e.g. account-v1.
+    RiotCluster,
+    Postgres,
+    Lolpros,
+}
+
+impl Dependency {
+    /// Whether a region can be updated without this dependency. Optional dependencies are skipped
+    /// while their breaker is open instead of pausing the region.
+    pub fn is_optional(self) -> bool {
+        matches!(self, Dependency::Lolpros)
+    }
+
+    fn backoff(self) -> Backoff {
+        match self {
+            Dependency::RiotPlatform | Dependency::RiotCluster => {
+                Backoff::new(Duration::from_secs(1), Duration::from_secs(60))
+            }
+            Dependency::Postgres => Backoff::new(Duration::from_secs(2), Duration::from_secs(120)),
+            Dependency::Lolpros => Backoff::new(Duration::from_secs(5), Duration::from_secs(300)),
+        }
+    }
+
+    /// Consecutive failures after which the breaker opens.
+    fn failure_threshold(self) -> u32 {
+        match self {
+            Dependency::Lolpros => 3,
+            _ => 5,
+        }
+    }
+
+    /// How long the breaker stays open before a trial request is let through.
+    fn open_duration(self) -> Duration {
+        match self {
+            Dependency::RiotPlatform | Dependency::RiotCluster => Duration::from_secs(60),
+            Dependency::Postgres => Duration::from_secs(30),
+            Dependency::Lolpros => Duration::from_secs(600),
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Dependency::RiotPlatform => "riot_platform",
+            Dependency::RiotCluster => "riot_cluster",
+            Dependency::Postgres => "postgres",
+            Dependency::Lolpros => "lolpros",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Finds the dependency that an error was attributed to with `.context(Dependency::...)`, falling
+/// back to `default` for errors that weren't attributed.
+pub fn failed_dependency(error: &anyhow::Error, default: Dependency) -> Dependency {
+    error
+        .downcast_ref::<Dependency>()
+        .copied()
+        .unwrap_or(default)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct CircuitBreaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    backoff: Backoff,
+}
+
+/// The circuit breakers of a single region, one per dependency.
+#[derive(Debug)]
+pub struct Breakers {
+    region: PlatformRoute,
+    breakers: HashMap<Dependency, CircuitBreaker>,
+}
+
+impl Breakers {
+    pub fn new(region: PlatformRoute) -> Self {
+        use strum::IntoEnumIterator;
+
+        Self {
+            region,
+            breakers: Dependency::iter()
+                .map(|dependency| {
+                    (
+                        dependency,
+                        CircuitBreaker {
+                            state: BreakerState::Closed,
+                            consecutive_failures: 0,
+                            backoff: dependency.backoff(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn breaker(&mut self, dependency: Dependency) -> &mut CircuitBreaker {
+        self.breakers
+            .get_mut(&dependency)
+            .expect("Every dependency has a breaker")
+    }
+
+    fn transition(&mut self, dependency: Dependency, state: BreakerState) {
+        let region = self.region;
+        let breaker = self.breaker(dependency);
+        if breaker.state == state {
+            return;
+        }
+        breaker.state = state;
+
+        match state {
+            BreakerState::Open { until } => warn!(
+                %region,
+                %dependency,
+                open_secs = until.saturating_duration_since(Instant::now()).as_secs(),
+                metric = "circuit_breaker_open",
+                "Circuit breaker opened."
+            ),
+            BreakerState::HalfOpen => info!(
+                %region,
+                %dependency,
+                "Circuit breaker half-open, letting a trial request through."
+            ),
+            BreakerState::Closed => info!(
+                %region,
+                %dependency,
+                metric = "circuit_breaker_closed",
+                "Circuit breaker closed."
+            ),
+        }
+    }
+
+    /// Whether requests to the dependency are currently let through. An open breaker whose open
+    /// duration has passed becomes half-open.
+    pub fn allows(&mut self, dependency: Dependency) -> bool {
+        match self.breaker(dependency).state {
+            BreakerState::Closed | BreakerState::HalfOpen => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                self.transition(dependency, BreakerState::HalfOpen);
+                true
+            }
+            BreakerState::Open { .. } => false,
+        }
+    }
+
+    /// Returns the required dependency with an open breaker that closes last, along with how long
+    /// it remains open. Breakers whose open duration has passed become half-open.
+    pub fn blocking(&mut self) -> Option<(Dependency, Duration)> {
+        use strum::IntoEnumIterator;
+
+        for dependency in Dependency::iter() {
+            self.allows(dependency);
+        }
+
+        let now = Instant::now();
+        self.breakers
+            .iter()
+            .filter(|(dependency, _)| !dependency.is_optional())
+            .filter_map(|(dependency, breaker)| match breaker.state {
+                BreakerState::Open { until } if until > now => Some((*dependency, until - now)),
+                _ => None,
+            })
+            .max_by_key(|(_, remaining)| *remaining)
+    }
+
+    /// Dependencies whose breakers are not closed.
+    pub fn degraded(&self) -> Vec<Dependency> {
+        let mut degraded: Vec<Dependency> = self
+            .breakers
+            .iter()
+            .filter(|(_, breaker)| breaker.state != BreakerState::Closed)
+            .map(|(dependency, _)| *dependency)
+            .collect();
+        degraded.sort_by_key(|dependency| dependency.to_string());
+        degraded
+    }
+
+    pub fn record_success(&mut self, dependency: Dependency) {
+        let breaker = self.breaker(dependency);
+        breaker.consecutive_failures = 0;
+        breaker.backoff.reset();
+        self.transition(dependency, BreakerState::Closed);
+    }
+
+    /// Records a failed request and returns how long to back off before retrying.
+    pub fn record_failure(&mut self, dependency: Dependency) -> Duration {
+        let breaker = self.breaker(dependency);
+        breaker.consecutive_failures += 1;
+        let delay = breaker.backoff.next_delay();
+
+        let should_open = breaker.state == BreakerState::HalfOpen
+            || breaker.consecutive_failures >= dependency.failure_threshold();
+        if should_open {
+            self.transition(
+                dependency,
+                BreakerState::Open {
+                    until: Instant::now() + dependency.open_duration(),
+                },
+            );
+        }
+
+        delay
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold() {
+        let mut breakers = Breakers::new(PlatformRoute::EUW1);
+
+        for _ in 0..Dependency::Postgres.failure_threshold() - 1 {
+            breakers.record_failure(Dependency::Postgres);
+            assert!(breakers.allows(Dependency::Postgres));
+        }
+        breakers.record_failure(Dependency::Postgres);
+
+        assert!(!breakers.allows(Dependency::Postgres));
+        assert_eq!(
+            breakers.blocking().map(|(dependency, _)| dependency),
+            Some(Dependency::Postgres)
+        );
+    }
+
+    #[test]
+    fn success_closes_breaker() {
+        let mut breakers = Breakers::new(PlatformRoute::EUW1);
+
+        for _ in 0..Dependency::RiotPlatform.failure_threshold() {
+            breakers.record_failure(Dependency::RiotPlatform);
+        }
+        breakers.record_success(Dependency::RiotPlatform);
+
+        assert!(breakers.allows(Dependency::RiotPlatform));
+        assert!(breakers.degraded().is_empty());
+    }
+
+    #[test]
+    fn optional_dependencies_do_not_block() {
+        let mut breakers = Breakers::new(PlatformRoute::EUW1);
+
+        for _ in 0..Dependency::Lolpros.failure_threshold() {
+            breakers.record_failure(Dependency::Lolpros);
+        }
+
+        assert!(!breakers.allows(Dependency::Lolpros));
+        assert!(breakers.blocking().is_none());
+        assert_eq!(breakers.degraded(), vec![Dependency::Lolpros]);
+    }
+
+    #[test]
+    fn finds_attributed_dependency() {
+        let attributed = anyhow!("All account API queries failed").context(Dependency::RiotCluster);
+        let unattributed = anyhow!("Connection reset");
+
+        assert_eq!(
+            failed_dependency(&attributed, Dependency::Postgres),
+            Dependency::RiotCluster
+        );
+        assert_eq!(
+            failed_dependency(&unattributed, Dependency::Postgres),
+            Dependency::Postgres
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
+use riven::consts::PlatformRoute;
+
+#[derive(Debug, Parser)]
+#[command(about = "Tracks dodges on the master+ ladders.")]
+pub struct Cli {
+    /// Defaults to `run`.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Update every configured region until stopped. The default.
+    Run,
+    /// Run a single cycle of a region and print the detected events.
+    Once {
+        #[arg(long)]
+        region: PlatformRoute,
+    },
+    /// Run a single cycle of a region, print the detected events and roll back.
+    DryRun {
+        #[arg(long)]
+        region: PlatformRoute,
+    },
+    /// Look up the missing summoners and Riot IDs of players that dodged.
+    Backfill {
+        #[arg(long)]
+        region: PlatformRoute,
+        /// Maximum amount of players to look up.
+        #[arg(long, default_value_t = 100)]
+        limit: u64,
+    },
+    /// Fill in the lowercased game names and tag lines that search uses for every stored Riot ID and
+    /// Riot ID history entry. Run it once after migrating to the history's search names.
+    BackfillSearchNames,
+    /// Export dodges as JSON lines.
+    Export {
+        /// Only export dodges of this region.
+        #[arg(long)]
+        region: Option<PlatformRoute>,
+        /// Only export dodges from this day on, e.g. 2024-07-01.
+        #[arg(long)]
+        since: Option<NaiveDate>,
+        /// File to write to instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Print a summary of a recorded ladder snapshot.
+    Snapshot {
+        path: PathBuf,
+        /// Print the ladder entry of this summoner.
+        #[arg(long)]
+        summoner_id: Option<String>,
+    },
+    /// Replay recorded ladder snapshots through dodge, promotion and demotion detection, and
+    /// compare the result with the stored events or write it to a fresh DB.
+    Replay {
+        #[arg(long)]
+        region: PlatformRoute,
+        /// First day to replay, e.g. 2024-07-01.
+        #[arg(long)]
+        from: NaiveDate,
+        /// Day to stop replaying at, exclusive.
+        #[arg(long)]
+        to: NaiveDate,
+        /// Directory to read snapshots from instead of the configured one.
+        #[arg(long)]
+        snapshot_dir: Option<PathBuf>,
+        /// Write the replayed events to this fresh DB instead of comparing them.
+        #[arg(long, conflicts_with = "report")]
+        database_url: Option<String>,
+        /// File to write the differences with the stored events to instead of stdout.
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Print the account that uses or last used a Riot ID, and the account's previous Riot IDs.
+    ResolveRiotId {
+        /// Riot ID as `<game name>#<tag line>`, in any case.
+        riot_id: String,
+    },
+    /// Validate the config and print a summary of it.
+    CheckConfig,
+    /// Check that the DB, the Riot API and lolpros are reachable.
+    Doctor,
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_region() {
+        let cli = Cli::parse_from(["dodgetracker-backend", "once", "--region", "EUW1"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Once {
+                region: PlatformRoute::EUW1
+            })
+        ));
+
+        let cli = Cli::parse_from(["dodgetracker-backend"]);
+        assert!(cli.command.is_none());
+    }
+}
@@ -0,0 +1,470 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use riven::consts::PlatformRoute;
+use sea_orm::sea_query::Query;
+use sea_orm::ActiveValue::{Set, Unchanged};
+use sea_orm::{
+    ActiveEnum, ActiveModelTrait, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter,
+    QueryOrder, QuerySelect, TransactionTrait,
+};
+use serde_json::json;
+
+use crate::circuit_breaker::Breakers;
+use crate::entities::{dodges, latest_updates, riot_id_history, riot_ids, summoners};
+use crate::pipeline::Pipeline;
+use crate::stages::StageOptions;
+use crate::util::with_timeout;
+use crate::{config, db, events, lolpros_client, riot_api, snapshots, stages};
+
+/// Runs a single cycle of a region and prints the detected events. A dry run rolls the cycle back
+/// instead of committing it.
+pub async fn once(region: PlatformRoute, dry_run: bool) -> Result<()> {
+    let db = db::get_db().await?;
+    let mut pipeline = Pipeline::new(region, stages::for_region(region, StageOptions::default()));
+    if dry_run {
+        pipeline = pipeline.rolled_back();
+    }
+
+    let report = pipeline
+        .run_cycle(db, &mut Breakers::new(region))
+        .await
+        .map_err(|failure| {
+            failure
+                .error
+                .context(format!("Stage {} failed", failure.stage))
+        })?;
+    if !dry_run {
+        crate::latest_updates::set_latest_update(region, db).await?;
+    }
+
+    let events = events::from_context(&report.ctx);
+    println!(
+        "{}: {} players on the ladder, {} events{}",
+        region,
+        report.ctx.api_players.len(),
+        events.len(),
+        if dry_run { " (rolled back)" } else { "" }
+    );
+    for event in events {
+        println!("  {}", event);
+    }
+
+    Ok(())
+}
+
+/// Looks up the summoners of players that dodged without a stored summoner, and the Riot IDs of
+/// stored summoners without one.
+pub async fn backfill(region: PlatformRoute, limit: u64) -> Result<()> {
+    let db = db::get_db().await?;
+
+    let summoner_ids: Vec<String> = dodges::Entity::find()
+        .select_only()
+        .column(dodges::Column::SummonerId)
+        .distinct()
+        .filter(dodges::Column::Region.eq(region.to_string()))
+        .filter(
+            dodges::Column::SummonerId.not_in_subquery(
+                Query::select()
+                    .column(summoners::Column::SummonerId)
+                    .from(summoners::Entity)
+                    .and_where(summoners::Column::SummonerId.is_not_null())
+                    .to_owned(),
+            ),
+        )
+        .limit(limit)
+        .into_tuple()
+        .all(db)
+        .await?;
+
+    let txn = db.begin().await?;
+
+    let summoner_ids: Vec<&str> = summoner_ids.iter().map(String::as_str).collect();
+    let mut puuids = if summoner_ids.is_empty() {
+        vec![]
+    } else {
+        crate::summoners::upsert_summoners(&summoner_ids, region, &txn).await?
+    };
+    println!("{}: backfilled {} summoners", region, puuids.len());
+
+    let without_riot_id: Vec<String> = summoners::Entity::find()
+        .select_only()
+        .column(summoners::Column::Puuid)
+        .filter(summoners::Column::Region.eq(region.to_string()))
+        .filter(
+            summoners::Column::Puuid.not_in_subquery(
+                Query::select()
+                    .column(riot_ids::Column::Puuid)
+                    .from(riot_ids::Entity)
+                    .to_owned(),
+            ),
+        )
+        .limit(limit)
+        .into_tuple()
+        .all(&txn)
+        .await?;
+    puuids.extend(without_riot_id);
+    puuids.sort();
+    puuids.dedup();
+
+    let riot_id_models = if puuids.is_empty() {
+        vec![]
+    } else {
+        crate::riot_ids::update_riot_ids(&puuids, region, &txn).await?
+    };
+    println!("{}: backfilled {} Riot IDs", region, riot_id_models.len());
+
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Sets the search names of the stored Riot IDs and their history entries that don't have them, e.g.
+/// history entries stored before it had search names, or that were normalised differently.
+pub async fn backfill_search_names() -> Result<()> {
+    let db = db::get_db().await?;
+    let batch_size = config::get().database.insert_chunk_size as u64;
+
+    let (mut checked, mut updated) = (0, 0);
+    let mut after = String::new();
+    loop {
+        let batch = riot_ids::Entity::find()
+            .filter(riot_ids::Column::Puuid.gt(after.as_str()))
+            .order_by_asc(riot_ids::Column::Puuid)
+            .limit(batch_size)
+            .all(db)
+            .await?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = last.puuid.clone();
+
+        let txn = db.begin().await?;
+        for riot_id in batch {
+            checked += 1;
+            let game_name = crate::riot_ids::search_name(&riot_id.game_name);
+            let tag_line = crate::riot_ids::search_name(&riot_id.tag_line);
+            if riot_id.lower_game_name.as_ref() == Some(&game_name)
+                && riot_id.lower_tag_line.as_ref() == Some(&tag_line)
+            {
+                continue;
+            }
+
+            riot_ids::ActiveModel {
+                puuid: Unchanged(riot_id.puuid),
+                lower_game_name: Set(Some(game_name)),
+                lower_tag_line: Set(Some(tag_line)),
+                ..Default::default()
+            }
+            .update(&txn)
+            .await?;
+            updated += 1;
+        }
+        txn.commit().await?;
+    }
+
+    println!(
+        "Updated the search names of {} of {} Riot IDs",
+        updated, checked
+    );
+
+    let (mut checked, mut updated) = (0, 0);
+    let mut after = 0;
+    loop {
+        let batch = riot_id_history::Entity::find()
+            .filter(riot_id_history::Column::Id.gt(after))
+            .order_by_asc(riot_id_history::Column::Id)
+            .limit(batch_size)
+            .all(db)
+            .await?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = last.id;
+
+        let txn = db.begin().await?;
+        for entry in batch {
+            checked += 1;
+            let game_name = crate::riot_ids::search_name(&entry.game_name);
+            let tag_line = crate::riot_ids::search_name(&entry.tag_line);
+            if entry.lower_game_name.as_ref() == Some(&game_name)
+                && entry.lower_tag_line.as_ref() == Some(&tag_line)
+            {
+                continue;
+            }
+
+            riot_id_history::ActiveModel {
+                id: Unchanged(entry.id),
+                lower_game_name: Set(Some(game_name)),
+                lower_tag_line: Set(Some(tag_line)),
+                ..Default::default()
+            }
+            .update(&txn)
+            .await?;
+            updated += 1;
+        }
+        txn.commit().await?;
+    }
+
+    println!(
+        "Updated the search names of {} of {} Riot ID history entries",
+        updated, checked
+    );
+    Ok(())
+}
+
+pub async fn resolve_riot_id(riot_id: &str) -> Result<()> {
+    let (game_name, tag_line) = riot_id.rsplit_once('#').ok_or_else(|| {
+        anyhow!(
+            "Riot ID {} is not of the form <game name>#<tag line>",
+            riot_id
+        )
+    })?;
+    let db = db::get_db().await?;
+
+    let Some(puuid) = crate::riot_ids::find_puuid(game_name, tag_line, db).await? else {
+        println!("{} was never seen", riot_id);
+        return Ok(());
+    };
+    println!("{} is or was used by {}", riot_id, puuid);
+
+    if let Some(current) = riot_ids::Entity::find_by_id(puuid.clone()).one(db).await? {
+        println!(
+            "  current Riot ID: {}#{}",
+            current.game_name, current.tag_line
+        );
+    }
+    let history = riot_id_history::Entity::find()
+        .filter(riot_id_history::Column::Puuid.eq(puuid))
+        .order_by_asc(riot_id_history::Column::ValidFrom)
+        .all(db)
+        .await?;
+    for entry in history {
+        println!(
+            "  {}#{} from {} until {}",
+            entry.game_name,
+            entry.tag_line,
+            entry.valid_from.to_rfc3339(),
+            entry
+                .valid_to
+                .map_or("now".to_string(), |valid_to| valid_to.to_rfc3339())
+        );
+    }
+
+    Ok(())
+}
+
+/// Writes every matching dodge as a JSON object on its own line, oldest first.
+pub async fn export(
+    region: Option<PlatformRoute>,
+    since: Option<NaiveDate>,
+    output: Option<&Path>,
+) -> Result<()> {
+    let db = db::get_db().await?;
+
+    let mut query = dodges::Entity::find().order_by_asc(dodges::Column::DodgeId);
+    if let Some(region) = region {
+        query = query.filter(dodges::Column::Region.eq(region.to_string()));
+    }
+    if let Some(since) = since {
+        query = query
+            .filter(dodges::Column::CreatedAt.gte(since.and_time(Default::default()).and_utc()));
+    }
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).with_context(|| format!("Failed to create {:?}", path))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let mut pages = query.paginate(db, 1000);
+    while let Some(dodges) = pages.fetch_and_next().await? {
+        for dodge in dodges {
+            let line = json!({
+                "dodge_id": dodge.dodge_id,
+                "summoner_id": dodge.summoner_id,
+                "region": dodge.region,
+                "rank_tier": dodge.rank_tier.to_value(),
+                "lp_before": dodge.lp_before,
+                "lp_after": dodge.lp_after,
+                "at_wins": dodge.at_wins,
+                "at_losses": dodge.at_losses,
+                "created_at": dodge.created_at.to_rfc3339(),
+            });
+            writeln!(writer, "{}", line)?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Prints the size of every ladder in a snapshot, and optionally a summoner's entry.
+pub fn snapshot(path: &Path, summoner_id: Option<&str>) -> Result<()> {
+    let snapshot = snapshots::load(path)?;
+
+    println!("{} fetched at {}", snapshot.region, snapshot.fetched_at);
+    for league in [
+        &snapshot.challenger,
+        &snapshot.grandmaster,
+        &snapshot.master,
+    ] {
+        println!("  {:<12} {} players", league.tier, league.entries.len());
+    }
+
+    if let Some(summoner_id) = summoner_id {
+        let entry = [
+            &snapshot.challenger,
+            &snapshot.grandmaster,
+            &snapshot.master,
+        ]
+        .into_iter()
+        .find_map(|league| {
+            league
+                .entries
+                .iter()
+                .find(|entry| entry.summoner_id == summoner_id)
+                .map(|entry| (league.tier, entry))
+        });
+        match entry {
+            Some((tier, entry)) => println!(
+                "{}: {} {} LP, {}W {}L",
+                summoner_id, tier, entry.league_points, entry.wins, entry.losses
+            ),
+            None => println!("{}: not on the ladder", summoner_id),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_config() {
+    let config = config::get();
+
+    println!("Config is valid.");
+    println!(
+        "Database schema {}, dodge LP ceiling {}",
+        config.database.schema, config.detection.dodge_lp_ceiling
+    );
+    let mut keys: Vec<String> = config
+        .riot
+        .keys
+        .iter()
+        .map(|key| {
+            if key.regions.is_empty() {
+                key.name.clone()
+            } else {
+                format!("{} ({})", key.name, key.regions.join(", "))
+            }
+        })
+        .collect();
+    if !config.riot.api_key.is_empty() {
+        keys.push(config::DEFAULT_KEY_NAME.to_string());
+    }
+    println!("API keys: {}", keys.join(", "));
+    for (name, region) in &config.regions {
+        let mut stages = vec![];
+        if region.stages.enrichment {
+            stages.push("enrichment");
+        }
+        if region.stages.lolpros {
+            stages.push("lolpros");
+        }
+        if region.stages.enrichment && config.pro_players.file.is_some() {
+            stages.push("curated_pros");
+        }
+        if region.stages.tier_changes {
+            stages.push("tier_changes");
+        }
+        if region.stages.player_counts {
+            stages.push("player_counts");
+        }
+        println!(
+            "  {:<5} every {}-{} ms, optional stages: {}",
+            name,
+            region.min_interval_ms,
+            region.max_interval_ms,
+            if stages.is_empty() {
+                "none".to_string()
+            } else {
+                stages.join(", ")
+            }
+        );
+    }
+}
+
+fn report_check(name: &str, result: &Result<String>) -> bool {
+    match result {
+        Ok(details) => println!("[ok]   {} {}", name, details),
+        Err(error) => println!("[fail] {} {:#}", name, error),
+    }
+    result.is_ok()
+}
+
+async fn check_db() -> Result<String> {
+    let db = db::get_db().await?;
+    db.ping().await?;
+
+    let updates = latest_updates::Entity::find().all(db).await?;
+    let now = chrono::Utc::now();
+    let ages: Vec<String> = updates
+        .iter()
+        .map(|update| {
+            let age = now.signed_duration_since(update.update_time);
+            format!("{} updated {}s ago", update.region, age.num_seconds())
+        })
+        .collect();
+
+    Ok(ages.join(", "))
+}
+
+async fn check_riot_api(region: PlatformRoute) -> Result<String> {
+    let riot_api = riot_api::for_region(region);
+    let result = with_timeout(
+        config::get().timeouts.league(),
+        riot_api.lol_status_v4().get_platform_data(region),
+    )
+    .await;
+    riot_api.record([&result]);
+    let status = result??;
+
+    Ok(format!(
+        "{} incidents, {} maintenances",
+        status.incidents.len(),
+        status.maintenances.len()
+    ))
+}
+
+async fn check_key_applications() -> Result<String> {
+    let mismatched = riot_api::mismatched_applications().await?;
+    if !mismatched.is_empty() {
+        return Err(anyhow!(
+            "keys of different applications: {}",
+            mismatched.join(", ")
+        ));
+    }
+    Ok(String::new())
+}
+
+/// Checks every external dependency and prints the outcome of each check. Returns whether all
+/// checks passed.
+pub async fn doctor() -> bool {
+    let mut healthy = report_check("postgres", &check_db().await);
+
+    for region in config::get().regions() {
+        let name = format!("riot_api {}", region);
+        healthy &= report_check(&name, &check_riot_api(region).await);
+    }
+    healthy &= report_check("riot_api_keys", &check_key_applications().await);
+
+    let lolpros = lolpros_client::get()
+        .check_reachable()
+        .await
+        .map(|_| String::new());
+    healthy &= report_check("lolpros", &lolpros);
+
+    healthy
+}
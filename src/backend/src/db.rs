@@ -1,24 +1,24 @@
-use std::env;
-
-use dotenv::from_path;
+use anyhow::{Context, Result};
 use sea_orm::{ConnectOptions, Database, DatabaseConnection};
 use tokio::sync::OnceCell;
 
+use crate::config;
+
 static DB: OnceCell<DatabaseConnection> = OnceCell::const_new();
 
-pub async fn get_db() -> &'static DatabaseConnection {
-    DB.get_or_init(|| async {
-        from_path("../../.env").ok();
-        let db_url = env::var("BACKEND_DATABASE_URL").expect("DB URL not set");
+/// Connects to a DB with the configured schema, e.g. a fresh DB to replay into.
+pub async fn connect(url: &str) -> Result<DatabaseConnection> {
+    Database::connect::<ConnectOptions>(
+        ConnectOptions::new(url.to_string())
+            .sqlx_logging(false)
+            .set_schema_search_path(config::get().database.schema.clone())
+            .to_owned(),
+    )
+    .await
+    .context("Failed to connect to DB")
+}
 
-        Database::connect::<ConnectOptions>(
-            ConnectOptions::new(db_url)
-                .sqlx_logging(false)
-                .set_schema_search_path("dodgetracker")
-                .to_owned(),
-        )
+pub async fn get_db() -> Result<&'static DatabaseConnection> {
+    DB.get_or_try_init(|| connect(&config::get().database.url))
         .await
-        .unwrap()
-    })
-    .await
 }
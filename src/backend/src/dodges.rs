@@ -2,17 +2,14 @@ use std::{collections::HashMap, time::Instant};
 
 use anyhow::Result;
 use riven::models::league_v4::LeagueItem;
-use sea_orm::{ActiveValue, DatabaseTransaction, EntityTrait};
+use sea_orm::{ActiveValue, ConnectionTrait, DatabaseTransaction, EntityTrait, QueryTrait};
 use tracing::info;
 use tracing::instrument;
 
+use crate::config;
 use crate::entities::sea_orm_active_enums::RankTierEnum;
 use crate::entities::{apex_tier_players, dodges};
 
-/// The maximum amount of LP a player can lose without playing a game and still be considered a dodge.
-/// If a player loses more LP than this, it's likely due to decay.
-const DODGE_LP_CEILING: i32 = 15;
-
 #[instrument(skip_all, fields(db_players = db_players.len(), api_players = api_players.len()))]
 pub async fn find_dodges(
     db_players: &HashMap<String, apex_tier_players::Model>,
@@ -22,6 +19,8 @@ pub async fn find_dodges(
 
     info!("Finding dodges...");
 
+    let dodge_lp_ceiling = config::get().detection.dodge_lp_ceiling;
+
     let dodges: Vec<dodges::ActiveModel> = api_players
         .values()
         .filter_map(|(new_data, rank_tier)| {
@@ -31,7 +30,7 @@ pub async fn find_dodges(
 
                 if new_data.league_points < old_data.current_lp as i32
                     && new_games_played == old_games_played as i32
-                    && (old_data.current_lp as i32 - new_data.league_points) <= DODGE_LP_CEILING
+                    && (old_data.current_lp as i32 - new_data.league_points) <= dodge_lp_ceiling
                 {
                     Some(dodges::ActiveModel {
                         summoner_id: ActiveValue::Set(old_data.summoner_id.clone()),
@@ -61,20 +60,28 @@ pub async fn find_dodges(
 }
 
 #[instrument(skip_all, fields(dodges = dodges.len()))]
+/// Inserts the dodges, returning their IDs.
 pub async fn insert_dodges(
     dodges: &[dodges::ActiveModel],
     txn: &DatabaseTransaction,
-) -> Result<()> {
+) -> Result<Vec<i64>> {
     if dodges.is_empty() {
-        return Ok(());
+        return Ok(vec![]);
     }
 
     let t1 = Instant::now();
     info!("Inserting dodges...");
 
-    dodges::Entity::insert_many(dodges.to_owned())
-        .exec(txn)
-        .await?;
+    let mut insert = dodges::Entity::insert_many(dodges.to_owned());
+    let statement = txn
+        .get_database_backend()
+        .build(insert.query().returning_col(dodges::Column::DodgeId));
+    let dodge_ids = txn
+        .query_all(statement)
+        .await?
+        .iter()
+        .map(|row| row.try_get("", "dodge_id"))
+        .collect::<Result<Vec<i64>, _>>()?;
 
     info!(
         perf = t1.elapsed().as_millis(),
@@ -83,7 +90,7 @@ pub async fn insert_dodges(
         "Inserted dodges into DB."
     );
 
-    Ok(())
+    Ok(dodge_ids)
 }
 
 // ----------------------------------------------------------------------------------------------------------------------------------------
@@ -118,6 +125,7 @@ mod tests {
                 wins: 10,
                 losses: 5,
                 rank_tier: rank_tier.clone(),
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: Utc::now().into(),
             },
@@ -167,6 +175,7 @@ mod tests {
                 wins: 10,
                 losses: 5,
                 rank_tier: rank_tier.clone(),
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: Utc::now().into(),
             },
@@ -214,6 +223,7 @@ mod tests {
                 wins: 10,
                 losses: 5,
                 rank_tier: rank_tier.clone(),
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: Utc::now().into(),
             },
@@ -261,6 +271,7 @@ mod tests {
                 wins: 10,
                 losses: 5,
                 rank_tier: rank_tier.clone(),
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: Utc::now().into(),
             },
@@ -308,6 +319,7 @@ mod tests {
                 wins: 10,
                 losses: 5,
                 rank_tier: rank_tier.clone(),
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: Utc::now().into(),
             },
@@ -357,6 +369,7 @@ mod tests {
                 wins: 10,
                 losses: 5,
                 rank_tier: rank_tier.clone(),
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: Utc::now().into(),
             },
@@ -0,0 +1,380 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use riven::consts::PlatformRoute;
+use sea_orm::sea_query::{LockBehavior, LockType};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue::Set, ColumnTrait, ConnectionTrait, DatabaseConnection,
+    DatabaseTransaction, DbBackend, EntityTrait, QueryFilter, QueryOrder, QuerySelect, Statement,
+    TransactionTrait,
+};
+use serde_json::json;
+use tokio::time::sleep;
+use tracing::{error, info, instrument, warn};
+
+use crate::config::{self, EnrichmentJobsConfig, StageToggles};
+use crate::entities::enrichment_jobs::{self, Column};
+use crate::entities::sea_orm_active_enums::EnrichmentJobStatusEnum;
+use crate::entities::{riot_ids as riot_id_entities, summoners as summoner_entities};
+use crate::{db, pro_players, riot_api, riot_ids, shutdown, summoners};
+
+/// Queues the dodges of the `$1` JSON array of dodge IDs that don't join to a Riot ID, i.e. whose
+/// summoner or account lookup failed.
+const ENQUEUE_UNENRICHED: &str = r#"
+    INSERT INTO enrichment_jobs (dodge_id, summoner_id, region)
+    SELECT d.dodge_id, d.summoner_id, d.region
+    FROM dodges d
+    LEFT JOIN summoners s ON d.summoner_id = s.summoner_id AND d.region = s.region
+    LEFT JOIN riot_ids r ON s.puuid = r.puuid
+    WHERE d.dodge_id IN (SELECT json_array_elements_text($1::json)::bigint) AND r.puuid IS NULL
+    ON CONFLICT (dodge_id) DO NOTHING
+"#;
+
+/// Sends the notification that the `dodge_insert` trigger skipped for an unenriched dodge.
+const NOTIFY_DODGE: &str = r#"
+    SELECT pg_notify('dodge_insert', payload::text)
+    FROM (SELECT dodge_notification($1) AS payload) t
+    WHERE payload IS NOT NULL
+"#;
+
+#[instrument(skip_all, fields(dodges = dodge_ids.len()))]
+pub async fn enqueue_unenriched(dodge_ids: &[i64], txn: &DatabaseTransaction) -> Result<u64> {
+    let result = txn
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            ENQUEUE_UNENRICHED,
+            [json!(dodge_ids).into()],
+        ))
+        .await?;
+
+    if result.rows_affected() > 0 {
+        warn!(
+            jobs = result.rows_affected(),
+            metric = "enrichment_jobs_enqueued",
+            "Queued enrichment of dodges whose lookups failed."
+        );
+    }
+    Ok(result.rows_affected())
+}
+
+/// Works the enrichment job queue until shutdown is requested.
+#[instrument(name = "enrichment_worker", skip_all)]
+pub async fn run_worker() {
+    let config = &config::get().enrichment_jobs;
+
+    loop {
+        tokio::select! {
+            _ = sleep(config.poll_interval()) => {}
+            _ = shutdown::requested() => {
+                info!("Shutdown requested, stopping enrichment worker.");
+                return;
+            }
+        }
+
+        let result = match db::get_db().await {
+            Ok(db) => work_due_jobs(db).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            error!(?error, "Working enrichment jobs failed.");
+        }
+    }
+}
+
+/// Claims the jobs that are due and retries their lookups. Jobs are locked until the transaction
+/// ends so that several workers can share the queue.
+async fn work_due_jobs(db: &DatabaseConnection) -> Result<()> {
+    let config = &config::get().enrichment_jobs;
+    let txn = db.begin().await?;
+
+    let jobs = enrichment_jobs::Entity::find()
+        .filter(Column::Status.eq(EnrichmentJobStatusEnum::Pending))
+        .filter(Column::NextAttemptAt.lte(Utc::now()))
+        .order_by_asc(Column::NextAttemptAt)
+        .limit(config.batch_size)
+        .lock_with_behavior(LockType::Update, LockBehavior::SkipLocked)
+        .all(&txn)
+        .await?;
+    if jobs.is_empty() {
+        return Ok(());
+    }
+
+    let t1 = Instant::now();
+    let mut by_region: BTreeMap<String, Vec<enrichment_jobs::Model>> = BTreeMap::new();
+    for job in jobs {
+        by_region.entry(job.region.clone()).or_default().push(job);
+    }
+
+    let mut notify = vec![];
+    let (mut retried, mut dead) = (0, 0);
+    for (region, jobs) in by_region {
+        let route = PlatformRoute::from_str(&region);
+        // Jobs stay untouched while the region waits for a working key
+        if route.is_ok_and(|route| !riot_api::has_valid_key(route)) {
+            continue;
+        }
+
+        for job in jobs {
+            let result = match route {
+                Ok(route) => enrich_job(&job.summoner_id, route, &txn).await,
+                Err(_) => Err(anyhow!("Unknown region {}", region)),
+            };
+
+            let now = Utc::now();
+            let dodge_id = job.dodge_id;
+            let update = match result {
+                Ok(()) => {
+                    notify.push(dodge_id);
+                    completed(job, now)
+                }
+                Err(error) => {
+                    let update = failed(job, format!("{:#}", error), config, now);
+                    if update.status == Set(EnrichmentJobStatusEnum::Dead) {
+                        dead += 1;
+                        error!(
+                            dodge_id,
+                            metric = "enrichment_job_dead",
+                            "Giving up on enriching dodge."
+                        );
+                    } else {
+                        retried += 1;
+                    }
+                    update
+                }
+            };
+            update.update(&txn).await?;
+        }
+    }
+    txn.commit().await?;
+
+    for &dodge_id in &notify {
+        if let Err(error) = db
+            .execute(Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                NOTIFY_DODGE,
+                [dodge_id.into()],
+            ))
+            .await
+        {
+            error!(dodge_id, ?error, "Failed to notify of late enriched dodge.");
+        }
+    }
+
+    info!(
+        perf = t1.elapsed().as_millis(),
+        done = notify.len(),
+        retried,
+        dead,
+        metric = "enrichment_jobs_worked",
+        "Worked enrichment jobs."
+    );
+    Ok(())
+}
+
+/// Retries the lookups of a job in a savepoint, so that a DB error only rolls back that job and its
+/// attempt can still be recorded in the claiming transaction.
+async fn enrich_job(
+    summoner_id: &str,
+    region: PlatformRoute,
+    txn: &DatabaseTransaction,
+) -> Result<()> {
+    let savepoint = txn.begin().await?;
+    let (enriched, error) = match enrich(&[summoner_id], region, &savepoint).await {
+        Ok(result) => {
+            savepoint.commit().await?;
+            result
+        }
+        Err(error) => {
+            savepoint.rollback().await?;
+            return Err(error);
+        }
+    };
+    if enriched.contains(summoner_id) {
+        Ok(())
+    } else {
+        Err(anyhow!(error.unwrap_or_else(|| {
+            "Summoner or account lookup failed".to_string()
+        })))
+    }
+}
+
+/// Looks up the summoners and Riot IDs that are missing, returning the summoners that now join to a
+/// Riot ID and the error of the lookup that failed, if any.
+async fn enrich(
+    summoner_ids: &[&str],
+    region: PlatformRoute,
+    txn: &DatabaseTransaction,
+) -> Result<(HashSet<String>, Option<String>)> {
+    let mut error = None;
+
+    let stored = stored_puuids(summoner_ids, region, txn).await?;
+    let missing: Vec<&str> = summoner_ids
+        .iter()
+        .copied()
+        .filter(|id| !stored.contains_key(*id))
+        .collect();
+    if !missing.is_empty() {
+        if let Err(e) = summoners::upsert_summoners(&missing, region, txn).await {
+            error = Some(format!("{:#}", e));
+        }
+    }
+
+    let stored = stored_puuids(summoner_ids, region, txn).await?;
+    let puuids: Vec<String> = stored.values().cloned().collect();
+    let with_riot_id = puuids_with_riot_id(&puuids, txn).await?;
+    let missing: Vec<String> = puuids
+        .iter()
+        .filter(|puuid| !with_riot_id.contains(*puuid))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        match riot_ids::update_riot_ids(&missing, region, txn).await {
+            Ok(models) if !models.is_empty() => {
+                for source in pro_players::sources(stage_toggles(region)) {
+                    if let Err(e) = source.tag(region, &models, txn).await {
+                        warn!(
+                            source = source.stage_name(),
+                            error = ?e,
+                            "Tagging pros among late enriched dodgers failed. Ignoring."
+                        );
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error = Some(format!("{:#}", e)),
+        }
+    }
+
+    let with_riot_id = puuids_with_riot_id(&puuids, txn).await?;
+    let enriched = stored
+        .into_iter()
+        .filter(|(_, puuid)| with_riot_id.contains(puuid))
+        .map(|(summoner_id, _)| summoner_id)
+        .collect();
+    Ok((enriched, error))
+}
+
+fn stage_toggles(region: PlatformRoute) -> StageToggles {
+    config::get()
+        .region(region)
+        .map(|region| region.stages)
+        .unwrap_or_default()
+}
+
+async fn stored_puuids(
+    summoner_ids: &[&str],
+    region: PlatformRoute,
+    txn: &DatabaseTransaction,
+) -> Result<HashMap<String, String>> {
+    Ok(summoner_entities::Entity::find()
+        .filter(summoner_entities::Column::Region.eq(region.to_string()))
+        .filter(summoner_entities::Column::SummonerId.is_in(summoner_ids.iter().copied()))
+        .all(txn)
+        .await?
+        .into_iter()
+        .filter_map(|summoner| summoner.summoner_id.map(|id| (id, summoner.puuid)))
+        .collect())
+}
+
+async fn puuids_with_riot_id(
+    puuids: &[String],
+    txn: &DatabaseTransaction,
+) -> Result<HashSet<String>> {
+    if puuids.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    Ok(riot_id_entities::Entity::find()
+        .filter(riot_id_entities::Column::Puuid.is_in(puuids.iter().cloned()))
+        .all(txn)
+        .await?
+        .into_iter()
+        .map(|riot_id| riot_id.puuid)
+        .collect())
+}
+
+fn completed(job: enrichment_jobs::Model, now: DateTime<Utc>) -> enrichment_jobs::ActiveModel {
+    let attempts = job.attempts + 1;
+    let mut update: enrichment_jobs::ActiveModel = job.into();
+    update.status = Set(EnrichmentJobStatusEnum::Done);
+    update.attempts = Set(attempts);
+    update.updated_at = Set(now.into());
+    update
+}
+
+/// Schedules the next attempt of a failed job, or moves it to the dead letter state once it has
+/// used up its attempts.
+fn failed(
+    job: enrichment_jobs::Model,
+    error: String,
+    config: &EnrichmentJobsConfig,
+    now: DateTime<Utc>,
+) -> enrichment_jobs::ActiveModel {
+    let attempts = job.attempts + 1;
+    let mut update: enrichment_jobs::ActiveModel = job.into();
+    if attempts >= config.max_attempts {
+        update.status = Set(EnrichmentJobStatusEnum::Dead);
+    } else {
+        update.next_attempt_at = Set((now + config.retry_delay(attempts)).into());
+    }
+    update.attempts = Set(attempts);
+    update.last_error = Set(Some(error));
+    update.updated_at = Set(now.into());
+    update
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn job(attempts: i32) -> enrichment_jobs::Model {
+        let now = Utc::now();
+        enrichment_jobs::Model {
+            job_id: 1,
+            dodge_id: 2,
+            summoner_id: "summoner1".to_string(),
+            region: PlatformRoute::EUW1.to_string(),
+            status: EnrichmentJobStatusEnum::Pending,
+            attempts,
+            next_attempt_at: now.into(),
+            last_error: None,
+            created_at: now.into(),
+            updated_at: now.into(),
+        }
+    }
+
+    #[test]
+    fn failed_jobs_are_retried_until_dead() {
+        let config = EnrichmentJobsConfig {
+            max_attempts: 3,
+            retry_base_secs: 60,
+            ..Default::default()
+        };
+        let now = Utc::now();
+
+        let retried = failed(job(1), "timed out".to_string(), &config, now);
+        assert_eq!(retried.attempts, Set(2));
+        assert_eq!(
+            retried.next_attempt_at,
+            Set((now + Duration::from_secs(120)).into())
+        );
+        assert_eq!(retried.last_error, Set(Some("timed out".to_string())));
+        assert!(!retried.status.is_set());
+
+        let dead = failed(job(2), "timed out".to_string(), &config, now);
+        assert_eq!(dead.status, Set(EnrichmentJobStatusEnum::Dead));
+
+        assert_eq!(
+            completed(job(2), now).status,
+            Set(EnrichmentJobStatusEnum::Done)
+        );
+    }
+}
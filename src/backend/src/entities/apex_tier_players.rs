@@ -18,6 +18,7 @@ pub struct Model {
     pub wins: i64,
     pub losses: i64,
     pub rank_tier: RankTierEnum,
+    pub refresh_attempted_at: Option<DateTimeWithTimeZone>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -10,8 +10,8 @@ pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub slug: String,
     pub name: String,
-    pub country: String,
-    pub position: PositionEnum,
+    pub country: Option<String>,
+    pub position: Option<PositionEnum>,
     pub created_at: DateTimeWithTimeZone,
     pub updated_at: DateTimeWithTimeZone,
 }
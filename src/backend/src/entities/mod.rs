@@ -5,10 +5,14 @@ pub mod prelude;
 pub mod apex_tier_players;
 pub mod demotions;
 pub mod dodges;
+pub mod enrichment_jobs;
 pub mod latest_updates;
 pub mod lol_pros;
 pub mod player_counts;
+pub mod pro_players;
 pub mod promotions;
+pub mod riot_id_history;
 pub mod riot_ids;
 pub mod sea_orm_active_enums;
+pub mod summoner_history;
 pub mod summoners;
@@ -12,8 +12,10 @@ pub struct Model {
     pub created_at: DateTimeWithTimeZone,
     pub updated_at: DateTimeWithTimeZone,
     pub lolpros_slug: Option<String>,
+    pub lolpros_checked_at: Option<DateTimeWithTimeZone>,
     pub lower_game_name: Option<String>,
     pub lower_tag_line: Option<String>,
+    pub lolpros_synced: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -2,6 +2,21 @@
 
 use sea_orm::entity::prelude::*;
 
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum)]
+#[sea_orm(
+    rs_type = "String",
+    db_type = "Enum",
+    enum_name = "enrichment_job_status_enum"
+)]
+pub enum EnrichmentJobStatusEnum {
+    #[sea_orm(string_value = "DEAD")]
+    Dead,
+    #[sea_orm(string_value = "DONE")]
+    Done,
+    #[sea_orm(string_value = "PENDING")]
+    Pending,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum)]
 #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "position_enum")]
 pub enum PositionEnum {
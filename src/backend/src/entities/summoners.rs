@@ -14,6 +14,7 @@ pub struct Model {
     pub summoner_level: i64,
     pub created_at: DateTimeWithTimeZone,
     pub updated_at: DateTimeWithTimeZone,
+    pub likely_smurf: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
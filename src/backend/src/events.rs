@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use riven::consts::PlatformRoute;
+use sea_orm::{ActiveEnum, ActiveValue::Set};
+use serde::Serialize;
+
+use crate::entities::{demotions, dodges, promotions};
+use crate::pipeline::CycleContext;
+
+/// A ladder event detected during a cycle.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Dodge {
+        summoner_id: String,
+        rank_tier: String,
+        lp_before: i64,
+        lp_after: i64,
+    },
+    Promotion {
+        summoner_id: String,
+        at_wins: i64,
+        at_losses: i64,
+    },
+    Demotion {
+        summoner_id: String,
+        at_wins: i64,
+        at_losses: i64,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Dodge {
+                summoner_id,
+                rank_tier,
+                lp_before,
+                lp_after,
+            } => write!(
+                f,
+                "dodge      {} ({}) {} -> {} LP",
+                summoner_id, rank_tier, lp_before, lp_after
+            ),
+            Event::Promotion {
+                summoner_id,
+                at_wins,
+                at_losses,
+            } => write!(
+                f,
+                "promotion  {} at {}W {}L",
+                summoner_id, at_wins, at_losses
+            ),
+            Event::Demotion {
+                summoner_id,
+                at_wins,
+                at_losses,
+            } => write!(
+                f,
+                "demotion   {} at {}W {}L",
+                summoner_id, at_wins, at_losses
+            ),
+        }
+    }
+}
+
+impl From<&dodges::Model> for Event {
+    fn from(dodge: &dodges::Model) -> Self {
+        Event::Dodge {
+            summoner_id: dodge.summoner_id.clone(),
+            rank_tier: dodge.rank_tier.to_value(),
+            lp_before: dodge.lp_before,
+            lp_after: dodge.lp_after,
+        }
+    }
+}
+
+impl From<&promotions::Model> for Event {
+    fn from(promotion: &promotions::Model) -> Self {
+        Event::Promotion {
+            summoner_id: promotion.summoner_id.clone(),
+            at_wins: promotion.at_wins,
+            at_losses: promotion.at_losses,
+        }
+    }
+}
+
+impl From<&demotions::Model> for Event {
+    fn from(demotion: &demotions::Model) -> Self {
+        Event::Demotion {
+            summoner_id: demotion.summoner_id.clone(),
+            at_wins: demotion.at_wins,
+            at_losses: demotion.at_losses,
+        }
+    }
+}
+
+/// Collects the dodges, promotions and demotions detected during a cycle.
+pub fn from_context(ctx: &CycleContext) -> Vec<Event> {
+    from_models(&ctx.dodges, &ctx.promotions, &ctx.demotions)
+}
+
+/// Converts detected but not yet inserted dodges, promotions and demotions to events.
+pub fn from_models(
+    dodges: &[dodges::ActiveModel],
+    promotions: &[promotions::ActiveModel],
+    demotions: &[demotions::ActiveModel],
+) -> Vec<Event> {
+    let dodges = dodges.iter().filter_map(|dodge| {
+        match (
+            &dodge.summoner_id,
+            &dodge.rank_tier,
+            &dodge.lp_before,
+            &dodge.lp_after,
+        ) {
+            (Set(summoner_id), Set(rank_tier), Set(lp_before), Set(lp_after)) => {
+                Some(Event::Dodge {
+                    summoner_id: summoner_id.clone(),
+                    rank_tier: rank_tier.to_value(),
+                    lp_before: *lp_before,
+                    lp_after: *lp_after,
+                })
+            }
+            _ => None,
+        }
+    });
+
+    let promotions = promotions.iter().filter_map(|promotion| {
+        match (
+            &promotion.summoner_id,
+            &promotion.at_wins,
+            &promotion.at_losses,
+        ) {
+            (Set(summoner_id), Set(at_wins), Set(at_losses)) => Some(Event::Promotion {
+                summoner_id: summoner_id.clone(),
+                at_wins: *at_wins,
+                at_losses: *at_losses,
+            }),
+            _ => None,
+        }
+    });
+
+    let demotions = demotions.iter().filter_map(|demotion| {
+        match (
+            &demotion.summoner_id,
+            &demotion.at_wins,
+            &demotion.at_losses,
+        ) {
+            (Set(summoner_id), Set(at_wins), Set(at_losses)) => Some(Event::Demotion {
+                summoner_id: summoner_id.clone(),
+                at_wins: *at_wins,
+                at_losses: *at_losses,
+            }),
+            _ => None,
+        }
+    });
+
+    dodges.chain(promotions).chain(demotions).collect()
+}
+
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    region: String,
+    detected_at: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+fn to_jsonl(region: PlatformRoute, detected_at: DateTime<Utc>, events: &[Event]) -> Result<String> {
+    let mut lines = String::new();
+    for event in events {
+        let record = EventRecord {
+            region: region.to_string(),
+            detected_at,
+            event,
+        };
+        lines.push_str(&serde_json::to_string(&record)?);
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+/// Appends the events to a JSONL file, one object per line. The lines of a cycle are written at
+/// once so that regions writing to the same file don't interleave.
+pub fn append_jsonl(path: &Path, region: PlatformRoute, events: &[Event]) -> Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let lines = to_jsonl(region, Utc::now(), events)?;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .with_context(|| format!("Failed to write events to {:?}", path))
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn writes_one_line_per_event() {
+        let events = vec![
+            Event::Dodge {
+                summoner_id: "a".to_string(),
+                rank_tier: "MASTER".to_string(),
+                lp_before: 100,
+                lp_after: 90,
+            },
+            Event::Demotion {
+                summoner_id: "b".to_string(),
+                at_wins: 10,
+                at_losses: 12,
+            },
+        ];
+        let detected_at = Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap();
+
+        let lines = to_jsonl(PlatformRoute::EUW1, detected_at, &events).unwrap();
+        let lines: Vec<&str> = lines.lines().collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"region":"EUW1","detected_at":"2024-07-01T12:00:00Z","type":"dodge","summoner_id":"a","rank_tier":"MASTER","lp_before":100,"lp_after":90}"#,
+                r#"{"region":"EUW1","detected_at":"2024-07-01T12:00:00Z","type":"demotion","summoner_id":"b","at_wins":10,"at_losses":12}"#,
+            ]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::{Context, Result};
 use chrono::Utc;
 use tracing::level_filters::LevelFilter;
 use tracing_appender::{
@@ -7,6 +8,8 @@ use tracing_appender::{
 use tracing_subscriber::fmt::time::FormatTime;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
+use crate::config::LoggingConfig;
+
 struct CustomTimeFormatter;
 
 impl FormatTime for CustomTimeFormatter {
@@ -16,14 +19,14 @@ impl FormatTime for CustomTimeFormatter {
     }
 }
 
-pub fn init() -> (WorkerGuard, WorkerGuard) {
+pub fn init(config: &LoggingConfig) -> Result<(WorkerGuard, WorkerGuard)> {
     let file_appender = RollingFileAppender::builder()
         .rotation(Rotation::HOURLY)
         .filename_prefix("dodgetracker-log")
         .filename_suffix("log")
-        .max_log_files(72) // three days worth of logs
-        .build(".log/")
-        .unwrap();
+        .max_log_files(config.max_files)
+        .build(&config.dir)
+        .with_context(|| format!("Failed to create log appender in {:?}", config.dir))?;
 
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
 
@@ -32,9 +35,14 @@ pub fn init() -> (WorkerGuard, WorkerGuard) {
         .rotation(Rotation::DAILY)
         .filename_prefix("dodgetracker-log-json")
         .filename_suffix("log")
-        .max_log_files(3)
-        .build(".log/json/")
-        .unwrap();
+        .max_log_files(config.json_max_files)
+        .build(&config.json_dir)
+        .with_context(|| {
+            format!(
+                "Failed to create JSON log appender in {:?}",
+                config.json_dir
+            )
+        })?;
 
     let (json_non_blocking, _json_guard) = tracing_appender::non_blocking(json_appender);
 
@@ -58,5 +66,5 @@ pub fn init() -> (WorkerGuard, WorkerGuard) {
         .with(json_layer)
         .init();
 
-    (_guard, _json_guard)
+    Ok((_guard, _json_guard))
 }
@@ -1,87 +1,88 @@
-use core::error;
-use std::time::{Duration, Instant};
+use std::collections::HashSet;
+use std::time::Instant;
 
-use anyhow::{anyhow, Context, Result};
-use futures::future::join_all;
-use sea_orm::{sea_query::OnConflict, ActiveValue::Set, DatabaseTransaction, EntityTrait};
-use tracing::{error, info, instrument, warn};
-use urlencoding::encode;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ActiveValue::Set,
+    ColumnTrait, DatabaseTransaction, EntityTrait, QueryFilter,
+};
+use tracing::{info, instrument, warn};
 
 use crate::{
-    config::INSERT_CHUNK_SIZE,
-    entities::{lol_pros, riot_ids, sea_orm_active_enums::PositionEnum},
+    circuit_breaker::Dependency,
+    config::{self, FreshnessConfig},
+    entities::{lol_pros, riot_ids},
+    lolpros_client::{self, Position},
     util::with_timeout,
 };
 
-#[derive(Debug)]
-struct LolProsProfile {
-    slug: String,
-    name: String,
-    country: String,
-    position: PositionEnum,
-}
-
-async fn get_lolpros_slug(
-    game_name: String,
-    tag_line: String,
+/// Looks up the lolpros profile of `game_name#tag_line`. Profiles without a known position are
+/// still stored, without one.
+async fn get_lolpros_profile(
+    game_name: &str,
+    tag_line: &str,
 ) -> Result<Option<lol_pros::ActiveModel>> {
-    let query = encode(format!("{}#{}", game_name, tag_line).as_str()).to_string();
-    let url = format!("https://api.lolpros.gg/es/search?query={}", query);
-
-    let response: Vec<serde_json::Value> = reqwest::get(&url)
-        .await
-        .context("Failed to fetch data from API")?
-        .json()
-        .await
-        .context("Failed to parse JSON response")?;
-
-    if let Some(profile) = response.first() {
-        let slug = profile
-            .get("slug")
-            .and_then(|v| v.as_str())
-            .context("Missing slug field")?
-            .to_string();
-
-        let name = profile
-            .get("name")
-            .and_then(|v| v.as_str())
-            .context("Missing name field")?
-            .to_string();
-
-        let country = profile
-            .get("country")
-            .and_then(|v| v.as_str())
-            .context("Missing country field")?
-            .to_string();
-
-        let position = match profile
-            .get("league_player")
-            .and_then(|lp| lp.get("position"))
-            .and_then(|v| v.as_str())
-            .context("Missing position field")?
-        {
-            "10_top" => PositionEnum::Top,
-            "20_jungle" => PositionEnum::Jungle,
-            "30_mid" => PositionEnum::Mid,
-            "40_adc" => PositionEnum::Bot,
-            "50_support" => PositionEnum::Support,
-            pos => {
-                error!(position = pos, "Unknown position.");
-                return Err(anyhow!("Unknown position for lolpros player"));
-            }
-        };
+    let Some(profile) = lolpros_client::get()
+        .find_profile(game_name, tag_line)
+        .await?
+    else {
+        return Ok(None);
+    };
 
-        Ok(Some(lol_pros::ActiveModel {
-            slug: Set(slug),
-            name: Set(name),
-            country: Set(country),
-            position: Set(position),
-            updated_at: Set(chrono::Utc::now().into()),
-            ..Default::default()
-        }))
-    } else {
-        Ok(None)
+    let position = profile.position().and_then(Position::to_db);
+    if profile.position() == Some(Position::Unknown) {
+        warn!(
+            slug = profile.slug,
+            "Unknown lolpros position, storing none."
+        );
     }
+
+    Ok(Some(lol_pros::ActiveModel {
+        slug: Set(profile.slug),
+        name: Set(profile.name),
+        country: Set(profile.country),
+        position: Set(position),
+        updated_at: Set(chrono::Utc::now().into()),
+        ..Default::default()
+    }))
+}
+
+/// Whether a stored Riot ID should be searched on lolpros again. Found profiles and misses are
+/// reused for their own TTL.
+fn needs_search(
+    riot_id: &riot_ids::Model,
+    freshness: &FreshnessConfig,
+    now: DateTime<Utc>,
+) -> bool {
+    let Some(checked_at) = riot_id.lolpros_checked_at else {
+        return true;
+    };
+    let ttl = match riot_id.lolpros_slug {
+        Some(_) => freshness.lolpros_hit_ttl(),
+        None => freshness.lolpros_miss_ttl(),
+    };
+    checked_at < now - ttl
+}
+
+/// The PUUIDs of `puuids` that were searched on lolpros recently enough to be skipped.
+async fn recently_searched(
+    puuids: &[&String],
+    txn: &DatabaseTransaction,
+) -> Result<HashSet<String>> {
+    let freshness = &config::get().freshness;
+    let now = Utc::now();
+    Ok(riot_ids::Entity::find()
+        .filter(riot_ids::Column::Puuid.is_in(puuids.iter().map(|puuid| puuid.as_str())))
+        .filter(riot_ids::Column::LolprosCheckedAt.is_not_null())
+        .all(txn)
+        .await?
+        .into_iter()
+        .filter(|riot_id| !needs_search(riot_id, freshness, now))
+        .map(|riot_id| riot_id.puuid)
+        .collect())
 }
 
 #[instrument(skip(accounts, txn), fields(accounts = accounts.len()))]
@@ -89,18 +90,53 @@ pub async fn upsert_lolpros_slugs(
     accounts: &[riot_ids::ActiveModel],
     txn: &DatabaseTransaction,
 ) -> Result<()> {
+    let accounts: Vec<(&String, &String, &String)> = accounts
+        .iter()
+        .filter_map(
+            |model| match (&model.puuid, &model.game_name, &model.tag_line) {
+                (Set(puuid), Set(game_name), Set(tag_line)) => Some((puuid, game_name, tag_line)),
+                _ => {
+                    warn!(account = ?model, "Account is missing its Riot ID, skipping.");
+                    None
+                }
+            },
+        )
+        .collect();
+
+    let puuids: Vec<&String> = accounts.iter().map(|(puuid, _, _)| *puuid).collect();
+    let cached = recently_searched(&puuids, txn).await?;
+    let accounts: Vec<_> = accounts
+        .into_iter()
+        .filter(|(puuid, _, _)| !cached.contains(*puuid))
+        .collect();
+    info!(
+        hits = cached.len(),
+        misses = accounts.len(),
+        metric = "lolpros_cache",
+        "Checked for recent lolpros searches."
+    );
+    if accounts.is_empty() {
+        return Ok(());
+    }
+
     let t1 = Instant::now();
     info!("Starting lolpros queries...");
 
-    let results: Vec<_> = join_all(accounts.iter().map(|model| {
-        let game_name = model.game_name.clone().unwrap();
-        let tag_line = model.tag_line.clone().unwrap();
-        with_timeout(
-            Duration::from_secs(5),
-            get_lolpros_slug(game_name, tag_line),
-        )
-    }))
-    .await;
+    let riot_ids: Vec<(String, String)> = accounts
+        .iter()
+        .map(|(_, game_name, tag_line)| (game_name.to_string(), tag_line.to_string()))
+        .collect();
+    let results: Vec<_> = stream::iter(riot_ids)
+        .map(|(game_name, tag_line)| async move {
+            with_timeout(
+                config::get().timeouts.lolpros(),
+                get_lolpros_profile(&game_name, &tag_line),
+            )
+            .await
+        })
+        .buffered(config::get().lolpros.max_concurrent_requests)
+        .collect()
+        .await;
 
     info!(
         perf = t1.elapsed().as_millis(),
@@ -109,41 +145,68 @@ pub async fn upsert_lolpros_slugs(
         "Lolpros API queries completed."
     );
 
+    if !results.is_empty() && results.iter().all(|result| !matches!(result, Ok(Ok(_)))) {
+        return Err(anyhow!("All lolpros API queries failed").context(Dependency::Lolpros));
+    }
+
+    let now = Utc::now();
     let mut riot_ids_to_upsert = vec![];
     let mut slugs_to_upsert = vec![];
+    let mut not_found = vec![];
 
-    for (model, result) in accounts.iter().zip(results) {
+    for ((puuid, game_name, tag_line), result) in accounts.into_iter().zip(results) {
         match result {
             Ok(Ok(Some(profile))) => {
+                let Set(slug) = &profile.slug else {
+                    warn!(puuid, "Lolpros profile is missing its slug. Ignoring.");
+                    continue;
+                };
                 riot_ids_to_upsert.push(riot_ids::ActiveModel {
-                    puuid: Set(model.puuid.clone().unwrap()),
-                    lolpros_slug: Set(Some(profile.slug.clone().unwrap())),
+                    puuid: Set(puuid.clone()),
+                    lolpros_slug: Set(Some(slug.clone())),
+                    lolpros_checked_at: Set(Some(now.into())),
+                    lolpros_synced: Set(false),
                     ..Default::default()
                 });
                 slugs_to_upsert.push(profile);
             }
+            Ok(Ok(None)) => not_found.push(puuid.clone()),
             Ok(Err(e)) => {
                 warn!(
-                    game_name = model.game_name.clone().unwrap(),
-                    tag_line = model.game_name.clone().unwrap(),
-                    puuid = model.puuid.clone().unwrap(),
+                    game_name,
+                    tag_line,
+                    puuid,
                     error = ?e,
                     "Lolpros API query failed. Ignoring.",
                 );
             }
             Err(e) => {
                 warn!(
-                    game_name = model.game_name.clone().unwrap(),
-                    tag_line = model.game_name.clone().unwrap(),
-                    puuid = model.puuid.clone().unwrap(),
+                    game_name,
+                    tag_line,
+                    puuid,
                     error = ?e,
                     "Lolpros API query timed out. Ignoring.",
                 );
             }
-            _ => {}
         }
     }
 
+    // Failed searches aren't cached so that they are retried on the next dodge. Accounts that are
+    // no longer on lolpros lose their old slug.
+    if !not_found.is_empty() {
+        riot_ids::Entity::update_many()
+            .col_expr(
+                riot_ids::Column::LolprosSlug,
+                Expr::value(Option::<String>::None),
+            )
+            .col_expr(riot_ids::Column::LolprosCheckedAt, Expr::value(now))
+            .col_expr(riot_ids::Column::LolprosSynced, Expr::value(false))
+            .filter(riot_ids::Column::Puuid.is_in(not_found))
+            .exec(txn)
+            .await?;
+    }
+
     if riot_ids_to_upsert.is_empty() && slugs_to_upsert.is_empty() {
         return Ok(());
     }
@@ -154,11 +217,16 @@ pub async fn upsert_lolpros_slugs(
         "Upserting lolpros slugs into riot_ids DB...",
     );
 
-    for chunk in riot_ids_to_upsert.chunks(INSERT_CHUNK_SIZE) {
+    for chunk in riot_ids_to_upsert.chunks(config::get().database.insert_chunk_size) {
         riot_ids::Entity::insert_many(chunk.to_vec())
             .on_conflict(
                 OnConflict::column(riot_ids::Column::Puuid)
-                    .update_columns([riot_ids::Column::LolprosSlug, riot_ids::Column::UpdatedAt])
+                    .update_columns([
+                        riot_ids::Column::LolprosSlug,
+                        riot_ids::Column::LolprosCheckedAt,
+                        riot_ids::Column::LolprosSynced,
+                        riot_ids::Column::UpdatedAt,
+                    ])
                     .to_owned(),
             )
             .exec(txn)
@@ -178,7 +246,7 @@ pub async fn upsert_lolpros_slugs(
         "Upserting lolpros profiles into lolpros DB...",
     );
 
-    for chunk in slugs_to_upsert.chunks(INSERT_CHUNK_SIZE) {
+    for chunk in slugs_to_upsert.chunks(config::get().database.insert_chunk_size) {
         lol_pros::Entity::insert_many(chunk.to_vec())
             .on_conflict(
                 OnConflict::column(lol_pros::Column::Slug)
@@ -203,3 +271,54 @@ pub async fn upsert_lolpros_slugs(
 
     Ok(())
 }
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn riot_id(lolpros_slug: Option<&str>, checked_hours_ago: Option<i64>) -> riot_ids::Model {
+        let now = Utc::now();
+        riot_ids::Model {
+            puuid: "puuid1".to_string(),
+            game_name: "Caps".to_string(),
+            tag_line: "EUW".to_string(),
+            created_at: now.into(),
+            updated_at: now.into(),
+            lolpros_slug: lolpros_slug.map(str::to_string),
+            lolpros_checked_at: checked_hours_ago
+                .map(|hours| (now - Duration::hours(hours)).into()),
+            lower_game_name: None,
+            lower_tag_line: None,
+            lolpros_synced: false,
+        }
+    }
+
+    #[test]
+    fn hits_and_misses_are_searched_again_after_their_ttls() {
+        let freshness = FreshnessConfig {
+            lolpros_hit_ttl_secs: 24 * 60 * 60,
+            lolpros_miss_ttl_secs: 7 * 24 * 60 * 60,
+            ..Default::default()
+        };
+        let now = Utc::now();
+
+        assert!(needs_search(&riot_id(None, None), &freshness, now));
+        assert!(!needs_search(
+            &riot_id(Some("caps"), Some(1)),
+            &freshness,
+            now
+        ));
+        assert!(needs_search(
+            &riot_id(Some("caps"), Some(25)),
+            &freshness,
+            now
+        ));
+        assert!(!needs_search(&riot_id(None, Some(25)), &freshness, now));
+        assert!(needs_search(&riot_id(None, Some(8 * 24)), &freshness, now));
+    }
+}
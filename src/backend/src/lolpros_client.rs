@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use urlencoding::encode;
+
+use crate::config;
+use crate::entities::sea_orm_active_enums::PositionEnum;
+
+const USER_AGENT: &str = concat!("dodgetracker-backend/", env!("CARGO_PKG_VERSION"));
+
+lazy_static! {
+    static ref CLIENT: LolprosClient = {
+        let config = config::get();
+        LolprosClient::new(
+            &config.lolpros.base_url,
+            config.http.proxy.as_deref(),
+            config.timeouts.lolpros(),
+        )
+        .expect("Failed to build lolpros HTTP client")
+    };
+}
+
+/// The lolpros client shared by all regions.
+pub fn get() -> &'static LolprosClient {
+    &CLIENT
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Position {
+    #[serde(rename = "10_top")]
+    Top,
+    #[serde(rename = "20_jungle")]
+    Jungle,
+    #[serde(rename = "30_mid")]
+    Mid,
+    #[serde(rename = "40_adc")]
+    Bot,
+    #[serde(rename = "50_support")]
+    Support,
+    /// Coaches, analysts and positions lolpros adds later.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Position {
+    pub fn to_db(self) -> Option<PositionEnum> {
+        match self {
+            Position::Top => Some(PositionEnum::Top),
+            Position::Jungle => Some(PositionEnum::Jungle),
+            Position::Mid => Some(PositionEnum::Mid),
+            Position::Bot => Some(PositionEnum::Bot),
+            Position::Support => Some(PositionEnum::Support),
+            Position::Unknown => None,
+        }
+    }
+}
+
+/// A Riot account linked to a lolpros profile. lolpros' PUUIDs are encrypted for its own API key, so
+/// accounts are matched by Riot ID.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Account {
+    #[serde(default)]
+    pub game_name: String,
+    #[serde(default)]
+    pub tag_line: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LeaguePlayer {
+    #[serde(default)]
+    pub position: Option<Position>,
+    /// Only included by the ladder endpoint.
+    #[serde(default)]
+    pub accounts: Vec<Account>,
+}
+
+/// A lolpros profile as returned by the search API. Fields we don't store are ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Profile {
+    pub slug: String,
+    pub name: String,
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub league_player: Option<LeaguePlayer>,
+}
+
+impl Profile {
+    pub fn position(&self) -> Option<Position> {
+        self.league_player.as_ref().and_then(|lp| lp.position)
+    }
+}
+
+pub struct LolprosClient {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl LolprosClient {
+    pub fn new(base_url: &str, proxy: Option<&str>, timeout: Duration) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(timeout)
+            .connect_timeout(timeout);
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).context("Invalid proxy URL")?);
+        }
+        Ok(Self {
+            http: builder.build()?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    fn search_url(&self, query: &str) -> String {
+        format!("{}/es/search?query={}", self.base_url, encode(query))
+    }
+
+    /// Searches lolpros for profiles matching `query`, e.g. a Riot ID.
+    pub async fn search(&self, query: &str) -> Result<Vec<Profile>> {
+        let body = self
+            .http
+            .get(self.search_url(query))
+            .send()
+            .await
+            .context("Failed to fetch data from API")?
+            .error_for_status()?
+            .text()
+            .await
+            .context("Failed to read response")?;
+        parse_profiles(&body)
+    }
+
+    /// A page of the lolpros ladder, which lists every player with their accounts. Pages start at 1
+    /// and the first empty page marks the end.
+    pub async fn ladder(&self, page: u32, per_page: u32) -> Result<Vec<Profile>> {
+        let body = self
+            .http
+            .get(format!(
+                "{}/es/ladder?page={}&per_page={}",
+                self.base_url, page, per_page
+            ))
+            .send()
+            .await
+            .context("Failed to fetch data from API")?
+            .error_for_status()?
+            .text()
+            .await
+            .context("Failed to read response")?;
+        parse_profiles(&body)
+    }
+
+    /// The profile of the player with the Riot ID `game_name#tag_line`, if any.
+    pub async fn find_profile(&self, game_name: &str, tag_line: &str) -> Result<Option<Profile>> {
+        Ok(self
+            .search(&format!("{}#{}", game_name, tag_line))
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    /// Checks that the search API responds.
+    pub async fn check_reachable(&self) -> Result<()> {
+        self.http
+            .get(self.search_url("test"))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+fn parse_profiles(body: &str) -> Result<Vec<Profile>> {
+    serde_json::from_str(body).context("Failed to parse JSON response")
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(body: &str) -> Vec<Profile> {
+        parse_profiles(body).unwrap()
+    }
+
+    #[test]
+    fn search_results_are_parsed() {
+        let profiles = fixture(include_str!("../fixtures/lolpros/search_pro.json"));
+
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0].slug, "caps");
+        assert_eq!(profiles[0].name, "Caps");
+        assert_eq!(profiles[0].country.as_deref(), Some("DK"));
+        assert_eq!(profiles[0].position(), Some(Position::Mid));
+        assert_eq!(
+            profiles[1].position().and_then(Position::to_db),
+            Some(PositionEnum::Bot)
+        );
+
+        assert!(profiles[0]
+            .league_player
+            .as_ref()
+            .unwrap()
+            .accounts
+            .is_empty());
+
+        assert!(fixture(include_str!("../fixtures/lolpros/search_empty.json")).is_empty());
+    }
+
+    #[test]
+    fn unknown_positions_and_missing_countries_are_tolerated() {
+        let coach = fixture(include_str!(
+            "../fixtures/lolpros/search_unknown_position.json"
+        ));
+        assert_eq!(coach[0].position(), Some(Position::Unknown));
+        assert_eq!(coach[0].position().and_then(Position::to_db), None);
+
+        let profiles = fixture(include_str!(
+            "../fixtures/lolpros/search_missing_country.json"
+        ));
+        assert_eq!(profiles[0].country, None);
+        assert_eq!(profiles[0].position(), Some(Position::Jungle));
+        assert_eq!(profiles[1].country, None);
+        assert_eq!(profiles[1].position(), None);
+    }
+
+    #[test]
+    fn ladder_pages_include_accounts() {
+        let profiles = fixture(include_str!("../fixtures/lolpros/ladder_page.json"));
+
+        assert_eq!(profiles.len(), 2);
+        let accounts = &profiles[0].league_player.as_ref().unwrap().accounts;
+        assert_eq!(
+            accounts,
+            &vec![
+                Account {
+                    game_name: "Caps".to_string(),
+                    tag_line: "EUW".to_string()
+                },
+                Account {
+                    game_name: "G2 Caps".to_string(),
+                    tag_line: "1337".to_string()
+                },
+            ]
+        );
+        assert_eq!(profiles[1].position(), Some(Position::Support));
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::{
+    ActiveValue::Set, ConnectionTrait, DatabaseConnection, DatabaseTransaction, DbBackend,
+    EntityTrait, Statement, TransactionTrait,
+};
+use serde_json::json;
+use tokio::time::sleep;
+use tracing::{error, info, instrument, warn};
+
+use crate::config::{self, LolprosSyncConfig};
+use crate::entities::lol_pros;
+use crate::lolpros_client::{self, LolprosClient, Profile};
+use crate::{db, riot_ids, shutdown};
+
+/// Links the stored Riot IDs matching the `$1` JSON array of accounts to their lolpros slug.
+const LINK_ACCOUNTS: &str = r#"
+    UPDATE riot_ids r
+    SET lolpros_slug = a.slug, lolpros_checked_at = now(), lolpros_synced = true
+    FROM json_to_recordset($1::json) AS a(game_name text, tag_line text, slug text)
+    WHERE r.lower_game_name = a.game_name AND r.lower_tag_line = a.tag_line
+        AND (r.lolpros_slug IS DISTINCT FROM a.slug OR NOT r.lolpros_synced)
+"#;
+
+/// Unlinks the Riot IDs linked by an earlier sync whose slug isn't in the `$1` JSON array of slugs.
+/// They count as a lolpros miss until they are searched again. Slugs found by searches are kept,
+/// since the ladder doesn't list every profile.
+const UNLINK_SLUGS: &str = r#"
+    UPDATE riot_ids
+    SET lolpros_slug = NULL, lolpros_checked_at = now(), lolpros_synced = false
+    WHERE lolpros_synced
+        AND lolpros_slug NOT IN (SELECT json_array_elements_text($1::json))
+"#;
+
+/// Deletes the profiles that aren't in the `$1` JSON array of slugs and no Riot ID links to.
+const DELETE_PROFILES: &str = r#"
+    DELETE FROM lol_pros p
+    WHERE p.slug NOT IN (SELECT json_array_elements_text($1::json))
+        AND NOT EXISTS (SELECT 1 FROM riot_ids r WHERE r.lolpros_slug = p.slug)
+"#;
+
+/// The lolpros ladder as it is stored: one profile per slug and the search names of the accounts
+/// linked to each.
+#[derive(Debug, Default)]
+struct Roster {
+    profiles: BTreeMap<String, Profile>,
+    accounts: Vec<(String, String, String)>,
+}
+
+impl Roster {
+    /// Players can move between pages while the ladder is paged through, so later duplicates are
+    /// dropped.
+    fn new(ladder: Vec<Profile>) -> Self {
+        let mut roster = Roster::default();
+        for profile in ladder {
+            if roster.profiles.contains_key(&profile.slug) {
+                continue;
+            }
+            let accounts = profile
+                .league_player
+                .iter()
+                .flat_map(|player| &player.accounts)
+                .filter(|account| !account.game_name.is_empty() && !account.tag_line.is_empty());
+            for account in accounts {
+                roster.accounts.push((
+                    riot_ids::search_name(&account.game_name),
+                    riot_ids::search_name(&account.tag_line),
+                    profile.slug.clone(),
+                ));
+            }
+            roster.profiles.insert(profile.slug.clone(), profile);
+        }
+        roster
+    }
+}
+
+/// Pages through the whole lolpros ladder. Returns whether the end of the ladder was reached within
+/// `max_pages`.
+async fn fetch_ladder(
+    client: &LolprosClient,
+    config: &LolprosSyncConfig,
+) -> Result<(Vec<Profile>, bool)> {
+    let mut ladder = vec![];
+    for page in 1..=config.max_pages {
+        let profiles = client.ladder(page, config.page_size).await?;
+        if profiles.is_empty() {
+            return Ok((ladder, true));
+        }
+        ladder.extend(profiles);
+    }
+    Ok((ladder, false))
+}
+
+/// Syncs the lolpros ladder at startup and then every `interval_hours`, until shutdown is requested.
+#[instrument(name = "lolpros_sync", skip_all)]
+pub async fn run() {
+    let config = &config::get().lolpros_sync;
+
+    loop {
+        let result = match db::get_db().await {
+            Ok(db) => sync(db).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            error!(?error, "Syncing the lolpros ladder failed.");
+        }
+
+        tokio::select! {
+            _ = sleep(config.interval()) => {}
+            _ = shutdown::requested() => {
+                info!("Shutdown requested, stopping lolpros sync.");
+                return;
+            }
+        }
+    }
+}
+
+async fn sync(db: &DatabaseConnection) -> Result<()> {
+    let t1 = Instant::now();
+    let (ladder, complete) =
+        fetch_ladder(lolpros_client::get(), &config::get().lolpros_sync).await?;
+    if ladder.is_empty() {
+        return Err(anyhow!("lolpros ladder is empty"));
+    }
+    let roster = Roster::new(ladder);
+
+    let txn = db.begin().await?;
+    let changed = upsert_profiles(&roster, &txn).await?;
+    let linked = execute(LINK_ACCOUNTS, json_accounts(&roster), &txn).await?;
+
+    // Players missing from a partial ladder may still be on lolpros
+    let (unlinked, removed) = if complete {
+        let slugs = json!(roster.profiles.keys().collect::<Vec<_>>());
+        (
+            execute(UNLINK_SLUGS, slugs.clone(), &txn).await?,
+            execute(DELETE_PROFILES, slugs, &txn).await?,
+        )
+    } else {
+        warn!(
+            max_pages = config::get().lolpros_sync.max_pages,
+            "Stopped before the end of the lolpros ladder, not unlinking any players."
+        );
+        (0, 0)
+    };
+    txn.commit().await?;
+
+    info!(
+        perf = t1.elapsed().as_millis(),
+        profiles = roster.profiles.len(),
+        changed,
+        linked,
+        unlinked,
+        removed,
+        metric = "lolpros_sync",
+        "Synced the lolpros ladder."
+    );
+    Ok(())
+}
+
+fn json_accounts(roster: &Roster) -> serde_json::Value {
+    roster
+        .accounts
+        .iter()
+        .map(|(game_name, tag_line, slug)| {
+            json!({ "game_name": game_name, "tag_line": tag_line, "slug": slug })
+        })
+        .collect()
+}
+
+async fn execute(sql: &str, value: serde_json::Value, txn: &DatabaseTransaction) -> Result<u64> {
+    Ok(txn
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            sql,
+            [value.into()],
+        ))
+        .await?
+        .rows_affected())
+}
+
+/// Upserts the profiles of the roster, returning how many were new or changed.
+async fn upsert_profiles(roster: &Roster, txn: &DatabaseTransaction) -> Result<u64> {
+    let now = chrono::Utc::now();
+    let models: Vec<lol_pros::ActiveModel> = roster
+        .profiles
+        .values()
+        .map(|profile| lol_pros::ActiveModel {
+            slug: Set(profile.slug.clone()),
+            name: Set(profile.name.clone()),
+            country: Set(profile.country.clone()),
+            position: Set(profile.position().and_then(|position| position.to_db())),
+            updated_at: Set(now.into()),
+            ..Default::default()
+        })
+        .collect();
+
+    let mut changed = 0;
+    for chunk in models.chunks(config::get().database.insert_chunk_size) {
+        changed += lol_pros::Entity::insert_many(chunk.to_vec())
+            .on_conflict(
+                OnConflict::column(lol_pros::Column::Slug)
+                    .update_columns([
+                        lol_pros::Column::Name,
+                        lol_pros::Column::Country,
+                        lol_pros::Column::Position,
+                        lol_pros::Column::UpdatedAt,
+                    ])
+                    .action_and_where(Expr::cust(
+                        "(lol_pros.name, lol_pros.country, lol_pros.position) IS DISTINCT FROM \
+                         (EXCLUDED.name, EXCLUDED.country, EXCLUDED.position)",
+                    ))
+                    .to_owned(),
+            )
+            .exec_without_returning(txn)
+            .await?;
+    }
+    Ok(changed)
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roster_has_one_profile_per_slug_and_search_names() {
+        let page: Vec<Profile> =
+            serde_json::from_str(include_str!("../fixtures/lolpros/ladder_page.json")).unwrap();
+        // The first page again, as if every player had moved down a page
+        let ladder = page.iter().chain(&page).cloned().collect();
+
+        let roster = Roster::new(ladder);
+
+        assert_eq!(
+            roster.profiles.keys().collect::<Vec<_>>(),
+            vec!["caps", "mikyx"]
+        );
+        assert_eq!(
+            roster.accounts,
+            vec![
+                ("caps".to_string(), "euw".to_string(), "caps".to_string()),
+                (
+                    "g2 caps".to_string(),
+                    "1337".to_string(),
+                    "caps".to_string()
+                ),
+            ]
+        );
+    }
+}
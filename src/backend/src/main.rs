@@ -1,213 +1,362 @@
-extern crate dotenv;
-use std::collections::HashMap;
+use std::process::ExitCode;
 use std::time::Instant;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::NaiveTime;
+use clap::Parser;
 use futures::future::join_all;
-use lazy_static::lazy_static;
 use riven::consts::PlatformRoute;
-use sea_orm::ActiveValue::Set;
-use sea_orm::TransactionTrait;
 use tokio::spawn;
-use tokio::time::sleep;
 use tokio::time::Duration;
+use tokio::time::{sleep, timeout};
 use tracing::instrument;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+use crate::cadence::Cadence;
+use crate::circuit_breaker::{Breakers, Dependency};
+use crate::cli::{Cli, Command};
+use crate::pipeline::Pipeline;
+use crate::stages::{ShadowLadder, StageOptions};
+use crate::status::RegionState;
 
 mod apex_tier_players;
+mod backoff;
+mod cadence;
+mod circuit_breaker;
+mod cli;
+mod commands;
 mod config;
 mod db;
 mod dodges;
+mod enrichment_jobs;
 mod entities;
+mod events;
 mod latest_updates;
 mod logger;
 mod lolpros;
+mod lolpros_client;
+mod lolpros_sync;
+mod pipeline;
 mod player_counts;
+mod player_refresh;
+mod pro_players;
 mod promotions_demotions;
+mod replay;
 mod riot_api;
 mod riot_ids;
+mod shutdown;
+#[cfg(test)]
+mod simulator;
+mod snapshots;
+mod stages;
+mod status;
 mod summoners;
+mod supervisor;
+mod systemd;
 mod util;
 
-const SUPPORTED_REGIONS: [PlatformRoute; 5] = [
-    PlatformRoute::EUW1,
-    PlatformRoute::EUN1,
-    PlatformRoute::NA1,
-    PlatformRoute::KR,
-    PlatformRoute::OC1,
-];
-
-lazy_static! {
-    static ref THROTTLES: HashMap<PlatformRoute, Duration> = {
-        let mut m = HashMap::new();
-        m.insert(PlatformRoute::EUW1, Duration::from_millis(7000));
-        m.insert(PlatformRoute::EUN1, Duration::from_millis(7000));
-        m.insert(PlatformRoute::NA1, Duration::from_millis(7000));
-        m.insert(PlatformRoute::KR, Duration::from_millis(7000));
-        m.insert(PlatformRoute::OC1, Duration::from_millis(7000));
-        m
-    };
-}
-
-const RETRY_WAIT_SECS: u64 = 5;
-
 #[instrument(skip_all, fields(duration = duration.as_millis()))]
 async fn sleep_thread(duration: Duration) {
     info!("Sleeping...");
-    sleep(duration).await;
+    tokio::select! {
+        _ = sleep(duration) => {}
+        _ = shutdown::requested() => info!("Shutdown requested, cutting sleep short."),
+    }
+}
+
+fn update_status(region: PlatformRoute, breakers: &Breakers) {
+    let degraded = breakers.degraded();
+    if degraded.is_empty() {
+        status::set_state(region, RegionState::Running);
+    } else {
+        let dependencies: Vec<String> = degraded.iter().map(|d| d.to_string()).collect();
+        status::set_state(
+            region,
+            RegionState::Degraded(format!("{} unavailable", dependencies.join(", "))),
+        );
+    }
+}
+
+/// Records a failure of `dependency` and waits out its backoff before the cycle is retried.
+async fn back_off(region: PlatformRoute, breakers: &mut Breakers, dependency: Dependency) {
+    let delay = breakers.record_failure(dependency);
+    update_status(region, breakers);
+    sleep_thread(delay).await;
 }
 
-#[allow(unreachable_code)]
 #[instrument(name = "run")]
-async fn run_region(region: PlatformRoute) {
+async fn run_region(region: PlatformRoute) -> Result<()> {
     info!("Getting DB connection...");
-    let db = db::get_db().await;
+    let db = db::get_db().await?;
+    let mut cadence = Cadence::new(region);
+    let mut breakers = Breakers::new(region);
+    let shadow = &config::get().shadow;
+    let shadow_ladder = shadow.enabled.then(ShadowLadder::default);
+    let pipeline = if shadow.enabled {
+        Pipeline::new(
+            region,
+            stages::for_region(
+                region,
+                StageOptions {
+                    shadow: shadow_ladder.clone(),
+                    ..Default::default()
+                },
+            ),
+        )
+        .rolled_back()
+    } else {
+        Pipeline::new(region, stages::for_region(region, StageOptions::default()))
+    };
+    info!(stages = ?pipeline.stage_names(), shadow = shadow.enabled, "Built region pipeline.");
 
     loop {
-        let t1 = Instant::now();
-
-        info!("Starting transaction...");
-        let txn = match db.begin().await {
-            Ok(txn) => txn,
-            Err(e) => {
-                error!(?e, "Failed to start transaction");
-                sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
-                continue;
-            }
-        };
-
-        let t2 = Instant::now();
-        let (api_players, (master_count, grandmaster_count, challenger_count)) =
-            match apex_tier_players::get_players_from_api(region).await {
-                Ok(r) => r,
-                Err(error) => {
-                    error!(?error, "Error getting players from the League API.");
-                    sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
-                    continue;
-                }
-            };
+        if shutdown::is_requested() {
+            info!("Shutdown requested, stopping region.");
+            return Ok(());
+        }
 
-        let db_players = match apex_tier_players::get_players_from_db(&txn, region).await {
-            Ok(res) => res,
-            Err(error) => {
-                error!(?error, "Error getting players from DB.");
-                sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
-                continue;
-            }
-        };
+        if let Some((dependency, remaining)) = breakers.blocking() {
+            warn!(
+                %dependency,
+                remaining_secs = remaining.as_secs(),
+                "Circuit breaker open, pausing region."
+            );
+            update_status(region, &breakers);
+            sleep_thread(remaining).await;
+            continue;
+        }
 
-        let dodges = dodges::find_dodges(&db_players, &api_players).await;
-
-        if !dodges.is_empty() {
-            let summoner_ids: Vec<&str> = dodges
-                .iter()
-                .filter_map(|dodge| match &dodge.summoner_id {
-                    Set(id) => Some(id.as_str()),
-                    _ => None,
-                })
-                .collect();
-
-            let riot_ids = match summoners::upsert_summoners(&summoner_ids, region, &txn).await {
-                Ok(res) => res,
-                Err(error) => {
-                    error!(?error, "Error updating summoners table");
-                    sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
-                    continue;
-                }
-            };
+        let t1 = Instant::now();
+        let key_generation = riot_api::key_generation();
 
-            let riot_id_models = match riot_ids::update_riot_ids(&riot_ids, &txn).await {
-                Ok(res) => res,
-                Err(error) => {
-                    error!(?error, "Error updating riot_ids table");
-                    sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
+        let report = match pipeline.run_cycle(db, &mut breakers).await {
+            Ok(report) => report,
+            Err(failure) if riot_api::is_key_rejected(&failure.error) => {
+                if riot_api::has_valid_key(region) {
+                    warn!(
+                        stage = failure.stage,
+                        "Riot API key was rejected, failing over to another key."
+                    );
                     continue;
                 }
-            };
-
-            if region == PlatformRoute::EUW1 {
-                if let Err(error) = lolpros::upsert_lolpros_slugs(&riot_id_models, &txn).await {
-                    error!(?error, "Error upserting Lolpros slugs. Ignoring.");
+                error!(
+                    stage = failure.stage,
+                    error = ?failure.error,
+                    "No valid Riot API key left, pausing region until one is replaced."
+                );
+                status::set_state(region, RegionState::ApiKeyInvalid);
+                tokio::select! {
+                    _ = riot_api::key_replaced_since(key_generation) => {}
+                    _ = shutdown::requested() => {}
                 }
+                continue;
             }
-
-            if let Err(error) = dodges::insert_dodges(&dodges, &txn).await {
-                error!(?error, "Error inserting dodges");
-                sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
+            Err(failure) => {
+                error!(
+                    stage = failure.stage,
+                    error = ?failure.error,
+                    "Region update failed."
+                );
+                back_off(region, &mut breakers, failure.dependency).await;
                 continue;
             }
-        }
-
-        if let Err(error) = apex_tier_players::upsert_players(&api_players, region, &txn).await {
-            error!(?error, "Error upserting players");
-            sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
-            continue;
-        }
-
-        if let Err(error) =
-            promotions_demotions::insert_promotions(&api_players, &db_players, region, &txn).await
-        {
-            error!(?error, "Error inserting promotions");
-            sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
-            continue;
-        }
-        if let Err(error) =
-            promotions_demotions::insert_demotions(&api_players, &db_players, region, &txn).await
-        {
-            error!(?error, "Error inserting demotions");
-            sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
-            continue;
-        }
-
-        if let Err(error) = player_counts::update_player_counts(
-            master_count,
-            grandmaster_count,
-            challenger_count,
-            region,
-            &txn,
-        )
-        .await
-        {
-            error!(?error, "Error updating player counts. Ignoring.");
-        }
+        };
 
-        info!("Committing transaction...");
-        if let Err(error) = txn.commit().await {
-            error!(?error, "Failed to commit transaction.");
-            sleep_thread(Duration::from_secs(RETRY_WAIT_SECS)).await;
-            continue;
-        }
+        update_status(region, &breakers);
         info!(
             perf = t1.elapsed().as_millis(),
             metric = "region_update",
             "Region update complete.",
         );
-        if let Err(error) = latest_updates::set_latest_update(region, db).await {
+        status::record_cycle(region);
+        if let Some(ladder) = &shadow_ladder {
+            ladder.apply(&report.ctx);
+            let events = events::from_context(&report.ctx);
+            if let Err(error) = events::append_jsonl(&shadow.output, region, &events) {
+                error!(?error, "Error writing shadow events. Ignoring.");
+            }
+        } else if let Err(error) = latest_updates::set_latest_update(region, db).await {
             error!(?error, "Error setting latest update time. Ignoring.");
         }
 
-        if let Some(sleep_duration) = THROTTLES[&region].checked_sub(t2.elapsed()) {
+        let ctx = &report.ctx;
+        cadence.record_requests(ctx.api_requests);
+        cadence.record_cycle(
+            cadence::games_played_since(&ctx.db_players, &ctx.api_players),
+            ctx.dodges.len(),
+        );
+        if let Some(sleep_duration) = cadence
+            .next_interval()
+            .checked_sub(report.fetched_at.elapsed())
+        {
             sleep_thread(sleep_duration).await;
         }
     }
 }
 
+/// Refuses to start when keys that fail over to each other belong to different Riot applications.
+/// Not being able to tell only warns, since the Riot API may be down for a while.
+async fn check_key_applications() -> Result<()> {
+    match riot_api::mismatched_applications().await {
+        Ok(mismatched) if mismatched.is_empty() => Ok(()),
+        Ok(mismatched) => Err(anyhow!(
+            "Riot API keys belong to different applications: {}",
+            mismatched.join(", ")
+        )),
+        Err(error) => {
+            warn!(
+                ?error,
+                "Failed to check whether the Riot API keys belong to the same application."
+            );
+            Ok(())
+        }
+    }
+}
+
 async fn run() -> Result<()> {
-    let mut tasks = vec![];
+    info!("Connecting to DB...");
+    db::get_db().await?;
+    check_key_applications().await?;
+
+    let regions = config::get().regions();
+    let handles: Vec<_> = regions
+        .iter()
+        .map(|&region| spawn(supervisor::supervise(region, run_region)))
+        .collect();
+    let abort_handles: Vec<_> = handles.iter().map(|handle| handle.abort_handle()).collect();
+    let supervisors = join_all(handles);
+    tokio::pin!(supervisors);
 
-    for &region in SUPPORTED_REGIONS.iter() {
-        tasks.push(spawn(async move { run_region(region).await }));
+    systemd::ready();
+    spawn(systemd::run_watchdog(regions));
+    spawn(riot_api::report_usage());
+    // Shadow cycles are rolled back, so they never queue any jobs
+    if !config::get().shadow.enabled {
+        spawn(enrichment_jobs::run_worker());
     }
+    if !config::get().shadow.enabled && config::get().player_refresh.enabled {
+        spawn(player_refresh::run());
+    }
+    let lolpros_regions = config::get().regions().into_iter().any(|region| {
+        config::get()
+            .region(region)
+            .is_some_and(|r| r.stages.lolpros)
+    });
+    if !config::get().shadow.enabled && config::get().lolpros_sync.enabled && lolpros_regions {
+        spawn(lolpros_sync::run());
+    }
+    if let Some(path) = &config::get().pro_players.file {
+        spawn(pro_players::reload_curated_on_change(path.clone()));
+    }
+    spawn(async {
+        if let Err(error) = riot_api::reload_keys_on_change().await {
+            error!(?error, "Riot API key reloading stopped.");
+        }
+    });
 
-    // Wait for all tasks to complete and collect the results
-    let _results = join_all(tasks).await;
+    tokio::select! {
+        _ = &mut supervisors => {
+            warn!("All region supervisors exited.");
+            return Ok(());
+        }
+        result = shutdown::wait_for_signal() => result?,
+    }
+    systemd::stopping();
+
+    info!("Waiting for in-flight cycles to finish...");
+    let deadline = config::get().service.shutdown_deadline();
+    if timeout(deadline, &mut supervisors).await.is_err() {
+        warn!(
+            deadline_secs = deadline.as_secs(),
+            "Shutdown deadline passed, rolling back in-flight cycles."
+        );
+        // Aborting drops the cycles' transactions, which rolls them back
+        for handle in &abort_handles {
+            handle.abort();
+        }
+        supervisors.await;
+    }
 
     Ok(())
 }
 
 #[tokio::main]
-async fn main() {
-    let (_guard, _json_guard) = logger::init();
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Run);
+
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{:?}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    config::init(config);
+
+    if let Command::CheckConfig = command {
+        commands::check_config();
+        return ExitCode::SUCCESS;
+    }
+
+    let (guard, json_guard) = match logger::init(&config::get().logging) {
+        Ok(guards) => guards,
+        Err(error) => {
+            eprintln!("{:?}", error);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    run().await.unwrap();
+    let result = match command {
+        Command::Run => run().await,
+        Command::Once { region } => commands::once(region, false).await,
+        Command::DryRun { region } => commands::once(region, true).await,
+        Command::Backfill { region, limit } => commands::backfill(region, limit).await,
+        Command::BackfillSearchNames => commands::backfill_search_names().await,
+        Command::Export {
+            region,
+            since,
+            output,
+        } => commands::export(region, since, output.as_deref()).await,
+        Command::Snapshot { path, summoner_id } => {
+            commands::snapshot(&path, summoner_id.as_deref())
+        }
+        Command::Replay {
+            region,
+            from,
+            to,
+            snapshot_dir,
+            database_url,
+            report,
+        } => {
+            replay::run(
+                region,
+                from.and_time(NaiveTime::MIN).and_utc(),
+                to.and_time(NaiveTime::MIN).and_utc(),
+                snapshot_dir
+                    .as_deref()
+                    .unwrap_or(&config::get().snapshots.dir),
+                database_url.as_deref(),
+                report.as_deref(),
+            )
+            .await
+        }
+        Command::ResolveRiotId { riot_id } => commands::resolve_riot_id(&riot_id).await,
+        Command::Doctor if commands::doctor().await => Ok(()),
+        Command::Doctor => Err(anyhow!("Some checks failed")),
+        Command::CheckConfig => unreachable!("Handled before the logger is initialized"),
+    };
+    if let Err(error) = &result {
+        error!(?error, "Backend failed.");
+        eprintln!("{:?}", error);
+    }
+
+    info!("Shutdown complete, flushing logs.");
+    drop(json_guard);
+    drop(guard);
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::FAILURE,
+    }
 }
@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use riven::consts::PlatformRoute;
+use sea_orm::{DatabaseConnection, DatabaseTransaction, TransactionTrait};
+use tracing::{error, info, instrument, warn};
+
+use crate::apex_tier_players::{ApiPlayers, TierCounts};
+use crate::circuit_breaker::{failed_dependency, Breakers, Dependency};
+use crate::entities::{apex_tier_players, demotions, dodges, promotions, riot_ids};
+
+/// What the pipeline does when a stage fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Roll back the transaction and retry the whole cycle after backing off.
+    AbortCycle,
+    /// Log the error and continue with the next stage.
+    Ignore,
+    /// Rerun the stage up to `attempts` more times, then abort the cycle.
+    Retry { attempts: u32, delay: Duration },
+}
+
+/// State that is passed between the stages of a single cycle.
+#[derive(Debug)]
+pub struct CycleContext {
+    pub region: PlatformRoute,
+    pub api_players: ApiPlayers,
+    pub tier_counts: TierCounts,
+    pub db_players: HashMap<String, apex_tier_players::Model>,
+    pub dodges: Vec<dodges::ActiveModel>,
+    /// IDs of the dodges inserted in this cycle.
+    pub dodge_ids: Vec<i64>,
+    pub puuids: Vec<String>,
+    pub riot_id_models: Vec<riot_ids::ActiveModel>,
+    pub promotions: Vec<promotions::ActiveModel>,
+    pub demotions: Vec<demotions::ActiveModel>,
+    /// Amount of platform API requests made during the cycle.
+    pub api_requests: u32,
+}
+
+impl CycleContext {
+    pub fn new(region: PlatformRoute) -> Self {
+        Self {
+            region,
+            api_players: HashMap::new(),
+            tier_counts: (0, 0, 0),
+            db_players: HashMap::new(),
+            dodges: vec![],
+            dodge_ids: vec![],
+            puuids: vec![],
+            riot_id_models: vec![],
+            promotions: vec![],
+            demotions: vec![],
+            api_requests: 0,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Name of the metric that the stage's duration is logged under.
+    fn metric(&self) -> &'static str;
+
+    fn error_policy(&self) -> ErrorPolicy;
+
+    /// The dependency the stage talks to, if any. Stages of optional dependencies are skipped
+    /// while the dependency's circuit breaker is open.
+    fn dependency(&self) -> Option<Dependency>;
+
+    /// Whether the stage has anything to do in this cycle.
+    fn should_run(&self, _ctx: &CycleContext) -> bool {
+        true
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()>;
+}
+
+/// A stage that failed and aborted its cycle.
+#[derive(Debug)]
+pub struct StageFailure {
+    pub stage: &'static str,
+    pub dependency: Dependency,
+    pub error: Error,
+}
+
+/// Summary of a committed cycle.
+#[derive(Debug)]
+pub struct CycleReport {
+    pub ctx: CycleContext,
+    /// When the ladder was fetched, used to pace cycles.
+    pub fetched_at: Instant,
+}
+
+pub struct Pipeline {
+    region: PlatformRoute,
+    stages: Vec<Box<dyn Stage>>,
+    /// Whether cycles are rolled back instead of committed.
+    rollback: bool,
+}
+
+impl Pipeline {
+    pub fn new(region: PlatformRoute, stages: Vec<Box<dyn Stage>>) -> Self {
+        Self {
+            region,
+            stages,
+            rollback: false,
+        }
+    }
+
+    /// Makes every cycle roll back its transaction after running all stages, so that nothing is
+    /// written to the DB.
+    pub fn rolled_back(mut self) -> Self {
+        self.rollback = true;
+        self
+    }
+
+    pub fn stage_names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    #[instrument(skip_all, fields(stage = stage.name()))]
+    async fn run_stage(
+        stage: &dyn Stage,
+        ctx: &mut CycleContext,
+        txn: &DatabaseTransaction,
+    ) -> Result<()> {
+        let t1 = Instant::now();
+        let mut retries = 0;
+
+        let result = loop {
+            match stage.run(ctx, txn).await {
+                Err(error) => match stage.error_policy() {
+                    ErrorPolicy::Retry { attempts, delay } if retries < attempts => {
+                        retries += 1;
+                        warn!(?error, retries, "Stage failed, retrying.");
+                        tokio::time::sleep(delay).await;
+                    }
+                    _ => break Err(error),
+                },
+                ok => break ok,
+            }
+        };
+
+        info!(
+            perf = t1.elapsed().as_millis(),
+            metric = stage.metric(),
+            ok = result.is_ok(),
+            "Stage finished."
+        );
+
+        result
+    }
+
+    /// Runs every stage in a single transaction and commits it, or rolls it back if the pipeline
+    /// was built with `rolled_back`. Circuit breakers are updated with
+    /// the outcome of every stage that talks to a dependency.
+    pub async fn run_cycle(
+        &self,
+        db: &DatabaseConnection,
+        breakers: &mut Breakers,
+    ) -> Result<CycleReport, StageFailure> {
+        let mut ctx = CycleContext::new(self.region);
+        let fetched_at = Instant::now();
+
+        info!("Starting transaction...");
+        let txn = db.begin().await.map_err(|e| StageFailure {
+            stage: "begin",
+            dependency: Dependency::Postgres,
+            error: e.into(),
+        })?;
+
+        for stage in &self.stages {
+            if !stage.should_run(&ctx) {
+                continue;
+            }
+            if let Some(dependency) = stage.dependency() {
+                if dependency.is_optional() && !breakers.allows(dependency) {
+                    info!(stage = stage.name(), %dependency, "Dependency unavailable, skipping stage.");
+                    continue;
+                }
+            }
+
+            match Self::run_stage(stage.as_ref(), &mut ctx, &txn).await {
+                Ok(()) => {
+                    if let Some(dependency) = stage.dependency() {
+                        breakers.record_success(dependency);
+                    }
+                }
+                Err(error) => {
+                    let dependency = failed_dependency(
+                        &error,
+                        stage.dependency().unwrap_or(Dependency::Postgres),
+                    );
+
+                    if stage.error_policy() == ErrorPolicy::Ignore {
+                        error!(stage = stage.name(), ?error, "Stage failed. Ignoring.");
+                        breakers.record_failure(dependency);
+                        continue;
+                    }
+
+                    return Err(StageFailure {
+                        stage: stage.name(),
+                        dependency,
+                        error,
+                    });
+                }
+            }
+        }
+
+        let result = if self.rollback {
+            info!("Rolling back transaction...");
+            txn.rollback().await
+        } else {
+            info!("Committing transaction...");
+            txn.commit().await
+        };
+        result.map_err(|e| StageFailure {
+            stage: "commit",
+            dependency: Dependency::Postgres,
+            error: e.into(),
+        })?;
+        breakers.record_success(Dependency::Postgres);
+
+        Ok(CycleReport { ctx, fetched_at })
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use anyhow::anyhow;
+    use sea_orm::{DatabaseBackend, MockDatabase};
+
+    use super::*;
+
+    struct TestStage {
+        name: &'static str,
+        policy: ErrorPolicy,
+        failures: u32,
+        runs: Arc<AtomicU32>,
+    }
+
+    impl TestStage {
+        fn new(name: &'static str, policy: ErrorPolicy, failures: u32) -> (Self, Arc<AtomicU32>) {
+            let runs = Arc::new(AtomicU32::new(0));
+            (
+                Self {
+                    name,
+                    policy,
+                    failures,
+                    runs: runs.clone(),
+                },
+                runs,
+            )
+        }
+    }
+
+    #[async_trait]
+    impl Stage for TestStage {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn metric(&self) -> &'static str {
+            "test_stage"
+        }
+
+        fn error_policy(&self) -> ErrorPolicy {
+            self.policy
+        }
+
+        fn dependency(&self) -> Option<Dependency> {
+            None
+        }
+
+        async fn run(&self, _ctx: &mut CycleContext, _txn: &DatabaseTransaction) -> Result<()> {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+            if run < self.failures {
+                Err(anyhow!("{} failed", self.name))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn mock_db() -> DatabaseConnection {
+        MockDatabase::new(DatabaseBackend::Postgres).into_connection()
+    }
+
+    #[tokio::test]
+    async fn abort_stops_later_stages() {
+        let (failing, _) = TestStage::new("failing", ErrorPolicy::AbortCycle, 1);
+        let (later, later_runs) = TestStage::new("later", ErrorPolicy::AbortCycle, 0);
+        let pipeline = Pipeline::new(
+            PlatformRoute::EUW1,
+            vec![Box::new(failing), Box::new(later)],
+        );
+
+        let result = pipeline
+            .run_cycle(&mock_db(), &mut Breakers::new(PlatformRoute::EUW1))
+            .await;
+
+        assert_eq!(result.unwrap_err().stage, "failing");
+        assert_eq!(later_runs.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn ignored_failures_continue_cycle() {
+        let (failing, _) = TestStage::new("failing", ErrorPolicy::Ignore, 1);
+        let (later, later_runs) = TestStage::new("later", ErrorPolicy::AbortCycle, 0);
+        let pipeline = Pipeline::new(
+            PlatformRoute::EUW1,
+            vec![Box::new(failing), Box::new(later)],
+        );
+
+        let result = pipeline
+            .run_cycle(&mock_db(), &mut Breakers::new(PlatformRoute::EUW1))
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(later_runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_before_aborting() {
+        let policy = ErrorPolicy::Retry {
+            attempts: 2,
+            delay: Duration::ZERO,
+        };
+        let (flaky, flaky_runs) = TestStage::new("flaky", policy, 2);
+        let (broken, broken_runs) = TestStage::new("broken", policy, u32::MAX);
+
+        let pipeline = Pipeline::new(PlatformRoute::EUW1, vec![Box::new(flaky)]);
+        let result = pipeline
+            .run_cycle(&mock_db(), &mut Breakers::new(PlatformRoute::EUW1))
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(flaky_runs.load(Ordering::SeqCst), 3);
+
+        let pipeline = Pipeline::new(PlatformRoute::EUW1, vec![Box::new(broken)]);
+        let result = pipeline
+            .run_cycle(&mock_db(), &mut Breakers::new(PlatformRoute::EUW1))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(broken_runs.load(Ordering::SeqCst), 3);
+    }
+}
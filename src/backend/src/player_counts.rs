@@ -7,6 +7,7 @@ use sea_orm::{
 use tracing::info;
 use tracing::instrument;
 
+use crate::config;
 use crate::entities::player_counts;
 use crate::entities::sea_orm_active_enums::RankTierEnum;
 
@@ -34,7 +35,7 @@ pub async fn update_player_counts(
 
     if let Some(latest_update_time) = latest_update_time {
         let time_diff = chrono::Utc::now() - latest_update_time;
-        if time_diff < chrono::Duration::hours(1) {
+        if time_diff < chrono::Duration::minutes(config::get().player_counts.interval_mins) {
             info!(
                 last_update_diff = time_diff.num_minutes(),
                 "Skipping player counts update."
@@ -0,0 +1,206 @@
+use std::time::Instant;
+
+use anyhow::Result;
+use riven::consts::PlatformRoute;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, Statement, TransactionTrait};
+use serde_json::json;
+use tokio::time::sleep;
+use tracing::{error, info, instrument, warn};
+
+use crate::circuit_breaker::{failed_dependency, Dependency};
+use crate::config::{self, PlayerRefreshConfig, RiotConfig};
+use crate::{db, riot_api, riot_ids, shutdown, summoners};
+
+/// Apex players of a region whose summoner or Riot ID is missing or older than `$2`, and that
+/// weren't already attempted since `$2`. Players that were never attempted come first, then players
+/// without rows, newest entrants first, then the oldest rows.
+const STALE_PLAYERS: &str = r#"
+    SELECT a.summoner_id
+    FROM apex_tier_players a
+    LEFT JOIN summoners s ON a.summoner_id = s.summoner_id AND a.region = s.region
+    LEFT JOIN riot_ids r ON s.puuid = r.puuid
+    WHERE a.region = $1 AND (r.puuid IS NULL OR LEAST(s.updated_at, r.updated_at) < $2)
+        AND (a.refresh_attempted_at IS NULL OR a.refresh_attempted_at < $2)
+    ORDER BY
+        a.refresh_attempted_at ASC NULLS FIRST,
+        r.puuid IS NULL DESC,
+        CASE WHEN r.puuid IS NULL THEN a.created_at END DESC,
+        LEAST(s.updated_at, r.updated_at) ASC
+    LIMIT $3
+"#;
+
+/// Records that the `$2` JSON array of summoner IDs of a region were attempted, so players whose
+/// lookups keep failing don't hold up the rest of the ladder.
+const RECORD_ATTEMPTS: &str = r#"
+    UPDATE apex_tier_players
+    SET refresh_attempted_at = now()
+    WHERE region = $1 AND summoner_id IN (SELECT json_array_elements_text($2::json))
+"#;
+
+/// Copies the Riot IDs of a region's apex players into their summoner names.
+const UPDATE_SUMMONER_NAMES: &str = r#"
+    UPDATE apex_tier_players a
+    SET summoner_name = r.game_name || '#' || r.tag_line
+    FROM summoners s
+    JOIN riot_ids r ON s.puuid = r.puuid
+    WHERE a.summoner_id = s.summoner_id AND a.region = s.region AND a.region = $1
+        AND a.summoner_name IS DISTINCT FROM r.game_name || '#' || r.tag_line
+"#;
+
+/// Players of each of `regions` regions that can be refreshed per rate limit window without using
+/// more than the configured share of the budget. A player takes a summoner lookup on the region's
+/// platform route and an account lookup on the regional route, which every region shares.
+fn players_per_window(riot: &RiotConfig, refresh: &PlayerRefreshConfig, regions: usize) -> u64 {
+    let requests = (riot.rate_limit_requests as f64 * refresh.budget_share).floor() as u64;
+    requests / regions.max(1) as u64
+}
+
+/// Refreshes the stalest players of every region with enrichment once per rate limit window, until
+/// shutdown is requested.
+#[instrument(name = "player_refresh", skip_all)]
+pub async fn run() {
+    let config = config::get();
+    let regions: Vec<PlatformRoute> = config
+        .regions()
+        .into_iter()
+        .filter(|&region| config.region(region).is_some_and(|r| r.stages.enrichment))
+        .collect();
+    let batch_size = players_per_window(&config.riot, &config.player_refresh, regions.len());
+    if batch_size == 0 {
+        info!("Player refresh budget is too small to refresh any players, not refreshing.");
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            _ = sleep(config.riot.rate_limit_window()) => {}
+            _ = shutdown::requested() => {
+                info!("Shutdown requested, stopping player refresh.");
+                return;
+            }
+        }
+
+        for &region in &regions {
+            if !riot_api::has_valid_key(region) {
+                continue;
+            }
+            let result = match db::get_db().await {
+                Ok(db) => refresh_region(region, batch_size, db).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                error!(%region, ?error, "Refreshing players failed.");
+            }
+        }
+    }
+}
+
+async fn refresh_region(
+    region: PlatformRoute,
+    batch_size: u64,
+    db: &DatabaseConnection,
+) -> Result<()> {
+    let t1 = Instant::now();
+    let stale_before = chrono::Utc::now() - config::get().player_refresh.stale_after();
+
+    let summoner_ids: Vec<String> = db
+        .query_all(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            STALE_PLAYERS,
+            [
+                region.to_string().into(),
+                stale_before.into(),
+                (batch_size as i64).into(),
+            ],
+        ))
+        .await?
+        .iter()
+        .map(|row| row.try_get("", "summoner_id"))
+        .collect::<Result<_, _>>()?;
+    if summoner_ids.is_empty() {
+        return Ok(());
+    }
+
+    // Recorded outside the transaction, so failed attempts are kept as well
+    db.execute(Statement::from_sql_and_values(
+        DbBackend::Postgres,
+        RECORD_ATTEMPTS,
+        [region.to_string().into(), json!(summoner_ids).into()],
+    ))
+    .await?;
+
+    let txn = db.begin().await?;
+    let summoner_ids: Vec<&str> = summoner_ids.iter().map(String::as_str).collect();
+    let puuids = lookups(
+        region,
+        summoners::upsert_summoners(&summoner_ids, region, &txn).await,
+    )?;
+    let riot_ids = if puuids.is_empty() {
+        vec![]
+    } else {
+        lookups(
+            region,
+            riot_ids::update_riot_ids(&puuids, region, &txn).await,
+        )?
+    };
+    let renamed = txn
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            UPDATE_SUMMONER_NAMES,
+            [region.to_string().into()],
+        ))
+        .await?
+        .rows_affected();
+    txn.commit().await?;
+
+    info!(
+        %region,
+        perf = t1.elapsed().as_millis(),
+        players = summoner_ids.len(),
+        riot_ids = riot_ids.len(),
+        renamed,
+        metric = "player_refresh",
+        "Refreshed apex players."
+    );
+    Ok(())
+}
+
+/// The stored rows of a batch of lookups. A batch whose lookups all failed, e.g. while the Riot API
+/// is down, stores nothing but isn't an error: its players are retried once they are stale again.
+fn lookups<T>(region: PlatformRoute, result: Result<Vec<T>>) -> Result<Vec<T>> {
+    match result {
+        Err(error) if failed_dependency(&error, Dependency::Postgres) != Dependency::Postgres => {
+            warn!(%region, ?error, "All player refresh lookups of the batch failed.");
+            Ok(vec![])
+        }
+        result => result,
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_stays_within_budget_share() {
+        let riot = RiotConfig {
+            rate_limit_requests: 100,
+            ..Default::default()
+        };
+        let refresh = |budget_share| PlayerRefreshConfig {
+            budget_share,
+            ..Default::default()
+        };
+
+        assert_eq!(players_per_window(&riot, &refresh(0.2), 1), 20);
+        assert_eq!(players_per_window(&riot, &refresh(0.25), 1), 25);
+        assert_eq!(players_per_window(&riot, &refresh(0.01), 1), 1);
+
+        // The account lookups of every region share the regional route
+        assert_eq!(players_per_window(&riot, &refresh(0.2), 3), 6);
+        assert_eq!(players_per_window(&riot, &refresh(0.01), 2), 0);
+    }
+}
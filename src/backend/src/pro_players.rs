@@ -0,0 +1,513 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use riven::consts::PlatformRoute;
+use sea_orm::{
+    sea_query::OnConflict, ActiveValue::Set, ConnectionTrait, DatabaseTransaction, DbBackend,
+    EntityTrait, Statement,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::interval;
+use tracing::{error, info, instrument};
+
+use crate::circuit_breaker::Dependency;
+use crate::config::StageToggles;
+use crate::entities::sea_orm_active_enums::PositionEnum;
+use crate::entities::{pro_players, riot_ids as riot_id_entities};
+use crate::{config, db, lolpros, riot_ids};
+
+const CURATED_FILE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// The pros of the curated file as it was last loaded.
+    static ref CURATED: RwLock<Option<Arc<Vec<CuratedPro>>>> = RwLock::new(None);
+}
+
+/// Where the pros among the players that dodged are looked up.
+#[async_trait]
+pub trait ProPlayerSource: Send + Sync {
+    /// The name of the pipeline stage tagging the source's pros.
+    fn stage_name(&self) -> &'static str;
+
+    fn metric(&self) -> &'static str;
+
+    /// The external service the source queries, if any.
+    fn dependency(&self) -> Option<Dependency>;
+
+    /// Tags the `accounts` of `region` that belong to pros.
+    async fn tag(
+        &self,
+        region: PlatformRoute,
+        accounts: &[riot_id_entities::ActiveModel],
+        txn: &DatabaseTransaction,
+    ) -> Result<()>;
+}
+
+/// Searches lolpros for the accounts and links them to the profiles in `lol_pros`.
+pub struct Lolpros;
+
+#[async_trait]
+impl ProPlayerSource for Lolpros {
+    fn stage_name(&self) -> &'static str {
+        "lolpros_enrichment"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_lolpros_enrichment"
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::Lolpros)
+    }
+
+    async fn tag(
+        &self,
+        _region: PlatformRoute,
+        accounts: &[riot_id_entities::ActiveModel],
+        txn: &DatabaseTransaction,
+    ) -> Result<()> {
+        lolpros::upsert_lolpros_slugs(accounts, txn).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Top,
+    Jungle,
+    Mid,
+    #[serde(alias = "adc")]
+    Bot,
+    Support,
+}
+
+impl From<Role> for PositionEnum {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Top => PositionEnum::Top,
+            Role::Jungle => PositionEnum::Jungle,
+            Role::Mid => PositionEnum::Mid,
+            Role::Bot => PositionEnum::Bot,
+            Role::Support => PositionEnum::Support,
+        }
+    }
+}
+
+/// Length of `pro_players.country`.
+const COUNTRY_MAX_LEN: usize = 5;
+
+/// A pro listed in the curated file, identified by either their PUUID or their Riot ID. Role and
+/// country are required, since the frontend only shows pros that have both.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CuratedPro {
+    pub puuid: Option<String>,
+    /// `<game name>#<tag line>`, matched case-insensitively.
+    pub riot_id: Option<String>,
+    pub name: String,
+    pub team: Option<String>,
+    pub role: Role,
+    pub country: String,
+    /// Only tags the pro in this region, e.g. `KR`. Any region if not set.
+    pub region: Option<String>,
+}
+
+impl CuratedPro {
+    fn matches(&self, region: PlatformRoute, puuid: &str, game_name: &str, tag_line: &str) -> bool {
+        if self
+            .region
+            .as_ref()
+            .is_some_and(|r| !r.eq_ignore_ascii_case(&region.to_string()))
+        {
+            return false;
+        }
+        match (&self.puuid, &self.riot_id) {
+            (Some(pro_puuid), _) => pro_puuid == puuid,
+            (None, Some(riot_id)) => riot_id.rsplit_once('#').is_some_and(|(name, tag)| {
+                riot_ids::search_name(name) == riot_ids::search_name(game_name)
+                    && riot_ids::search_name(tag) == riot_ids::search_name(tag_line)
+            }),
+            (None, None) => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CuratedPros {
+    #[serde(default)]
+    pros: Vec<CuratedPro>,
+}
+
+fn parse_curated(contents: &str) -> Result<Vec<CuratedPro>> {
+    let pros = toml::from_str::<CuratedPros>(contents)?.pros;
+    for (i, pro) in pros.iter().enumerate() {
+        match (&pro.puuid, &pro.riot_id) {
+            (None, None) | (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "pros[{}] ({}) must have either a puuid or a riot_id",
+                    i,
+                    pro.name
+                ));
+            }
+            (None, Some(riot_id)) if !riot_id.contains('#') => {
+                return Err(anyhow!(
+                    "pros[{}] ({}) has riot_id {}, expected <game name>#<tag line>",
+                    i,
+                    pro.name,
+                    riot_id
+                ));
+            }
+            _ => {}
+        }
+        if pro.country.chars().count() > COUNTRY_MAX_LEN {
+            return Err(anyhow!(
+                "pros[{}] ({}) has country {}, which is longer than {} characters",
+                i,
+                pro.name,
+                pro.country,
+                COUNTRY_MAX_LEN
+            ));
+        }
+        if let Some(region) = &pro.region {
+            region.parse::<PlatformRoute>().map_err(|_| {
+                anyhow!(
+                    "pros[{}] ({}) has region {}, which is not a platform route",
+                    i,
+                    pro.name,
+                    region
+                )
+            })?;
+        }
+    }
+    Ok(pros)
+}
+
+/// Deletes the curated pros whose entry was removed from the file, given as the `$1` JSON array of
+/// PUUIDs and search names of Riot IDs.
+const DELETE_REMOVED: &str = r#"
+    DELETE FROM pro_players p
+    WHERE p.source = 'curated' AND NOT EXISTS (
+        SELECT 1
+        FROM json_to_recordset($1::json) AS c(puuid text, game_name text, tag_line text)
+        LEFT JOIN riot_ids r ON r.puuid = p.puuid
+        WHERE c.puuid = p.puuid
+            OR (r.lower_game_name = c.game_name AND r.lower_tag_line = c.tag_line)
+    )
+"#;
+
+/// The entries of the curated file as they are matched by [`DELETE_REMOVED`].
+fn json_entries(pros: &[CuratedPro]) -> serde_json::Value {
+    pros.iter()
+        .map(|pro| {
+            let (game_name, tag_line) = pro
+                .riot_id
+                .as_ref()
+                .and_then(|riot_id| riot_id.rsplit_once('#'))
+                .map(|(name, tag)| (riot_ids::search_name(name), riot_ids::search_name(tag)))
+                .unzip();
+            json!({ "puuid": pro.puuid, "game_name": game_name, "tag_line": tag_line })
+        })
+        .collect()
+}
+
+/// Tags the accounts listed in a curated TOML file, storing them in `pro_players`.
+pub struct CuratedFile {
+    path: PathBuf,
+}
+
+impl CuratedFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn load(path: &Path) -> Result<Vec<CuratedPro>> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        parse_curated(&contents).with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    /// The pros as last loaded, loading the file if that hasn't happened yet, e.g. in commands that
+    /// don't run the reloader.
+    fn pros(&self) -> Result<Arc<Vec<CuratedPro>>> {
+        if let Some(pros) = CURATED.read().unwrap_or_else(|e| e.into_inner()).clone() {
+            return Ok(pros);
+        }
+        let pros = Arc::new(Self::load(&self.path)?);
+        *CURATED.write().unwrap_or_else(|e| e.into_inner()) = Some(pros.clone());
+        Ok(pros)
+    }
+}
+
+/// Loads the curated file and untags the pros that were removed from it.
+async fn reload_curated(path: &Path) -> Result<()> {
+    let pros = CuratedFile::load(path)?;
+    let entries = json_entries(&pros);
+    info!(
+        pros = pros.len(),
+        metric = "curated_pros_reloaded",
+        "Loaded curated pros."
+    );
+    *CURATED.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(pros));
+
+    // Shadow mode doesn't write to the DB outside of its rolled back cycles
+    if config::get().shadow.enabled {
+        return Ok(());
+    }
+    let removed = db::get_db()
+        .await?
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            DELETE_REMOVED,
+            [entries.into()],
+        ))
+        .await?
+        .rows_affected();
+    if removed > 0 {
+        info!(
+            removed,
+            metric = "curated_pros_removed",
+            "Removed pros no longer in the curated file."
+        );
+    }
+    Ok(())
+}
+
+/// Loads the curated file on startup and whenever it changes. A file that fails to load is retried
+/// on the next poll, while dodgers keep being tagged with the pros that were loaded before.
+#[instrument(name = "curated_pro_reloader", skip_all)]
+pub async fn reload_curated_on_change(path: PathBuf) {
+    let mut loaded: Option<Option<SystemTime>> = None;
+    let mut polls = interval(CURATED_FILE_POLL_INTERVAL);
+
+    loop {
+        polls.tick().await;
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if loaded == Some(modified) {
+            continue;
+        }
+
+        match reload_curated(&path).await {
+            Ok(()) => loaded = Some(modified),
+            Err(error) => error!(?error, "Failed to reload curated pros."),
+        }
+    }
+}
+
+/// The `pro_players` rows of the `accounts` that are listed in `pros`.
+fn curated_matches(
+    pros: &[CuratedPro],
+    region: PlatformRoute,
+    accounts: &[riot_id_entities::ActiveModel],
+) -> Vec<pro_players::ActiveModel> {
+    let now = chrono::Utc::now();
+    accounts
+        .iter()
+        .filter_map(|account| {
+            let (Set(puuid), Set(game_name), Set(tag_line)) =
+                (&account.puuid, &account.game_name, &account.tag_line)
+            else {
+                return None;
+            };
+            let pro = pros
+                .iter()
+                .find(|pro| pro.matches(region, puuid, game_name, tag_line))?;
+            Some(pro_players::ActiveModel {
+                puuid: Set(puuid.clone()),
+                source: Set("curated".to_string()),
+                name: Set(pro.name.clone()),
+                team: Set(pro.team.clone()),
+                country: Set(Some(pro.country.clone())),
+                position: Set(Some(pro.role.into())),
+                updated_at: Set(now.into()),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+#[async_trait]
+impl ProPlayerSource for CuratedFile {
+    fn stage_name(&self) -> &'static str {
+        "curated_pro_enrichment"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_curated_pro_enrichment"
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        None
+    }
+
+    #[instrument(name = "curated_pros", skip_all, fields(accounts = accounts.len()))]
+    async fn tag(
+        &self,
+        region: PlatformRoute,
+        accounts: &[riot_id_entities::ActiveModel],
+        txn: &DatabaseTransaction,
+    ) -> Result<()> {
+        let t1 = Instant::now();
+        let pros = self.pros()?;
+
+        let models = curated_matches(&pros, region, accounts);
+        if models.is_empty() {
+            return Ok(());
+        }
+
+        pro_players::Entity::insert_many(models.clone())
+            .on_conflict(
+                OnConflict::column(pro_players::Column::Puuid)
+                    .update_columns([
+                        pro_players::Column::Source,
+                        pro_players::Column::Name,
+                        pro_players::Column::Team,
+                        pro_players::Column::Country,
+                        pro_players::Column::Position,
+                        pro_players::Column::UpdatedAt,
+                    ])
+                    .to_owned(),
+            )
+            .exec(txn)
+            .await?;
+
+        info!(
+            perf = t1.elapsed().as_millis(),
+            pros = models.len(),
+            metric = "curated_pros_upserted",
+            "Upserted curated pros into DB."
+        );
+        Ok(())
+    }
+}
+
+/// The sources that tag the pros of a region: lolpros where its stage is turned on, and the curated
+/// file in every region if one is configured.
+pub fn sources(toggles: StageToggles) -> Vec<Arc<dyn ProPlayerSource>> {
+    let mut sources: Vec<Arc<dyn ProPlayerSource>> = vec![];
+    if toggles.lolpros {
+        sources.push(Arc::new(Lolpros));
+    }
+    if let Some(path) = &config::get().pro_players.file {
+        sources.push(Arc::new(CuratedFile::new(path.clone())));
+    }
+    sources
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(puuid: &str, game_name: &str, tag_line: &str) -> riot_id_entities::ActiveModel {
+        riot_id_entities::ActiveModel {
+            puuid: Set(puuid.to_string()),
+            game_name: Set(game_name.to_string()),
+            tag_line: Set(tag_line.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn curated_pros_match_by_puuid_or_riot_id() {
+        let pros = parse_curated(include_str!("../pro_players.example.toml")).unwrap();
+        let accounts = [
+            account("puuid-faker", "Hide on bush", "KR1"),
+            account("puuid-2", "ZEUS", "kr1"),
+            account("puuid-3", "Someone", "KR1"),
+        ];
+
+        let matches = curated_matches(&pros, PlatformRoute::KR, &accounts);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].name, Set("Faker".to_string()));
+        assert_eq!(matches[0].position, Set(Some(PositionEnum::Mid)));
+        assert_eq!(matches[1].puuid, Set("puuid-2".to_string()));
+        assert_eq!(
+            matches[1].team,
+            Set(Some("Hanwha Life Esports".to_string()))
+        );
+
+        // Zeus is only listed for KR
+        assert_eq!(
+            curated_matches(&pros, PlatformRoute::EUW1, &accounts).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn removed_curated_pros_are_matched_by_search_names() {
+        let pros = parse_curated(include_str!("../pro_players.example.toml")).unwrap();
+
+        let entries = json_entries(&pros);
+
+        assert_eq!(
+            entries[0],
+            json!({ "puuid": pros[0].puuid, "game_name": null, "tag_line": null })
+        );
+        assert_eq!(
+            entries[1],
+            json!({ "puuid": null, "game_name": "zeus", "tag_line": "kr1" })
+        );
+    }
+
+    #[test]
+    fn curated_pros_need_one_identifier() {
+        let error = parse_curated(
+            r#"
+            [[pros]]
+            name = "Nobody"
+            role = "mid"
+            country = "KR"
+            "#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("either a puuid or a riot_id"));
+
+        let error = parse_curated(
+            r#"
+            [[pros]]
+            riot_id = "Faker"
+            name = "Faker"
+            role = "mid"
+            country = "KR"
+            "#,
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("expected <game name>#<tag line>"));
+    }
+    #[test]
+    fn curated_pros_need_role_and_country() {
+        let error = parse_curated(
+            r#"
+            [[pros]]
+            riot_id = "Faker#KR1"
+            name = "Faker"
+            country = "KR"
+            "#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("missing field `role`"));
+
+        let error = parse_curated(
+            r#"
+            [[pros]]
+            riot_id = "Faker#KR1"
+            name = "Faker"
+            role = "mid"
+            country = "South Korea"
+            "#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("longer than 5 characters"));
+    }
+}
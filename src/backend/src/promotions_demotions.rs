@@ -2,19 +2,23 @@ use std::{collections::HashMap, time::Instant};
 
 use anyhow::Result;
 use chrono::{DateTime, FixedOffset};
-use riven::{consts::PlatformRoute, models::league_v4::LeagueItem};
+use riven::consts::PlatformRoute;
 use sea_orm::{ActiveValue::Set, ColumnTrait, DatabaseTransaction, EntityTrait, QueryFilter};
 use tracing::{info, instrument};
 
 use crate::{
-    config::INSERT_CHUNK_SIZE,
-    entities::{apex_tier_players, demotions, promotions, sea_orm_active_enums::RankTierEnum},
+    apex_tier_players::ApiPlayers,
+    config,
+    entities::{apex_tier_players, demotions, promotions},
 };
 
+/// When each player was demoted, keyed by summoner ID.
+pub type DemotionHistory = HashMap<String, Vec<DateTime<FixedOffset>>>;
+
 fn has_promoted(
     summoner_id: &String,
     db_players: &HashMap<String, apex_tier_players::Model>,
-    demotions: &HashMap<String, Vec<DateTime<FixedOffset>>>,
+    demotions: &DemotionHistory,
 ) -> bool {
     match db_players.get(summoner_id) {
         None => true,
@@ -27,10 +31,7 @@ fn has_promoted(
     }
 }
 
-fn has_demoted(
-    player_only_in_db: &apex_tier_players::Model,
-    demotions: &HashMap<String, Vec<DateTime<FixedOffset>>>,
-) -> bool {
+fn has_demoted(player_only_in_db: &apex_tier_players::Model, demotions: &DemotionHistory) -> bool {
     match demotions.get(&player_only_in_db.summoner_id) {
         None => true,
         Some(demotions) => demotions
@@ -39,12 +40,27 @@ fn has_demoted(
     }
 }
 
+/// Records `demotions` made at `at` in `history`, for pipelines whose demotions aren't stored.
+pub fn record_demotions(
+    history: &mut DemotionHistory,
+    demotions: &[demotions::ActiveModel],
+    at: DateTime<FixedOffset>,
+) {
+    for demotion in demotions {
+        if let Set(summoner_id) = &demotion.summoner_id {
+            history.entry(summoner_id.clone()).or_default().push(at);
+        }
+    }
+}
+
 // TODO: only execute this once and pass it down
+/// The stored demotions of `region` together with the `unstored` ones.
 #[instrument(skip_all)]
 async fn get_demotions(
     region: PlatformRoute,
+    unstored: &DemotionHistory,
     txn: &DatabaseTransaction,
-) -> Result<HashMap<String, Vec<DateTime<FixedOffset>>>> {
+) -> Result<DemotionHistory> {
     let t1 = Instant::now();
 
     info!("Getting demotions from DB...");
@@ -53,15 +69,18 @@ async fn get_demotions(
         .all(txn)
         .await?;
 
-    let result = demotions.into_iter().fold(
-        HashMap::new(),
-        |mut acc: HashMap<String, Vec<DateTime<FixedOffset>>>, demotion| {
-            acc.entry(demotion.summoner_id)
-                .or_default()
-                .push(demotion.created_at);
-            acc
-        },
-    );
+    let mut result =
+        demotions
+            .into_iter()
+            .fold(HashMap::new(), |mut acc: DemotionHistory, demotion| {
+                acc.entry(demotion.summoner_id)
+                    .or_default()
+                    .push(demotion.created_at);
+                acc
+            });
+    for (summoner_id, times) in unstored {
+        result.entry(summoner_id.clone()).or_default().extend(times);
+    }
 
     info!(
         perf = t1.elapsed().as_millis(),
@@ -73,22 +92,20 @@ async fn get_demotions(
     Ok(result)
 }
 
-#[instrument(skip_all, fields(api_players = api_players.len(), db_players = db_players.len()))]
-pub async fn insert_promotions(
-    api_players: &HashMap<String, (LeagueItem, RankTierEnum)>,
+/// Finds the players that entered the apex tiers since the stored ladder was updated.
+pub fn find_promotions(
+    api_players: &ApiPlayers,
     db_players: &HashMap<String, apex_tier_players::Model>,
     region: PlatformRoute,
-    txn: &DatabaseTransaction,
-) -> Result<()> {
-    let demotions = get_demotions(region, txn).await?;
-
+    demotions: &DemotionHistory,
+) -> Vec<promotions::ActiveModel> {
     let t1 = Instant::now();
     info!("Finding promotions...");
 
     let promotions_models: Vec<promotions::ActiveModel> = api_players
         .iter()
         .filter_map(|(summoner_id, (stats, _))| {
-            if has_promoted(summoner_id, db_players, &demotions) {
+            if has_promoted(summoner_id, db_players, demotions) {
                 Some(promotions::ActiveModel {
                     summoner_id: Set(summoner_id.clone()),
                     region: Set(region.to_string()),
@@ -109,21 +126,17 @@ pub async fn insert_promotions(
         "Found promotions."
     );
 
-    for chunk in promotions_models.chunks(INSERT_CHUNK_SIZE) {
-        promotions::Entity::insert_many(chunk.to_vec())
-            .exec(txn)
-            .await?;
-    }
-    Ok(())
+    promotions_models
 }
 
-#[instrument(skip_all, fields(api_players = api_players.len(), db_players = db_players.len()))]
-pub async fn insert_demotions(
-    api_players: &HashMap<String, (LeagueItem, RankTierEnum)>,
+/// Finds the stored players that left the apex tiers and haven't been demoted since the stored
+/// ladder was updated.
+pub fn find_demotions(
+    api_players: &ApiPlayers,
     db_players: &HashMap<String, apex_tier_players::Model>,
     region: PlatformRoute,
-    txn: &DatabaseTransaction,
-) -> Result<()> {
+    demotions: &DemotionHistory,
+) -> Vec<demotions::ActiveModel> {
     let t1 = Instant::now();
     info!("Finding players not in API...");
 
@@ -140,14 +153,13 @@ pub async fn insert_demotions(
         "Found players not in API."
     );
 
-    let demotions = get_demotions(region, txn).await?;
     info!("Detecting demotions...");
 
     let t2 = Instant::now();
     let demotion_models: Vec<demotions::ActiveModel> = players_not_in_api
         .iter()
         .filter_map(|(summoner_id, player)| {
-            if has_demoted(player, &demotions) {
+            if has_demoted(player, demotions) {
                 Some(demotions::ActiveModel {
                     summoner_id: Set(summoner_id.clone()),
                     region: Set(region.to_string()),
@@ -168,13 +180,46 @@ pub async fn insert_demotions(
         "Detected demotions."
     );
 
-    for chunk in demotion_models.chunks(INSERT_CHUNK_SIZE) {
+    demotion_models
+}
+
+#[instrument(skip_all, fields(api_players = api_players.len(), db_players = db_players.len()))]
+pub async fn insert_promotions(
+    api_players: &ApiPlayers,
+    db_players: &HashMap<String, apex_tier_players::Model>,
+    region: PlatformRoute,
+    unstored_demotions: &DemotionHistory,
+    txn: &DatabaseTransaction,
+) -> Result<Vec<promotions::ActiveModel>> {
+    let demotions = get_demotions(region, unstored_demotions, txn).await?;
+    let promotions_models = find_promotions(api_players, db_players, region, &demotions);
+
+    for chunk in promotions_models.chunks(config::get().database.insert_chunk_size) {
+        promotions::Entity::insert_many(chunk.to_vec())
+            .exec(txn)
+            .await?;
+    }
+    Ok(promotions_models)
+}
+
+#[instrument(skip_all, fields(api_players = api_players.len(), db_players = db_players.len()))]
+pub async fn insert_demotions(
+    api_players: &ApiPlayers,
+    db_players: &HashMap<String, apex_tier_players::Model>,
+    region: PlatformRoute,
+    unstored_demotions: &DemotionHistory,
+    txn: &DatabaseTransaction,
+) -> Result<Vec<demotions::ActiveModel>> {
+    let demotions = get_demotions(region, unstored_demotions, txn).await?;
+    let demotion_models = find_demotions(api_players, db_players, region, &demotions);
+
+    for chunk in demotion_models.chunks(config::get().database.insert_chunk_size) {
         demotions::Entity::insert_many(chunk.to_vec())
             .exec(txn)
             .await?;
     }
 
-    Ok(())
+    Ok(demotion_models)
 }
 
 // ----------------------------------------------------------------------------------------------------------------------------------------
@@ -227,6 +272,7 @@ mod test {
                 wins: 10,
                 losses: 5,
                 rank_tier: RankTierEnum::Challenger,
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: str_to_utc("2024-07-01"),
             },
@@ -260,6 +306,7 @@ mod test {
                 wins: 10,
                 losses: 5,
                 rank_tier: RankTierEnum::Challenger,
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: str_to_utc("2024-07-03"),
             },
@@ -292,6 +339,7 @@ mod test {
                 wins: 10,
                 losses: 5,
                 rank_tier: RankTierEnum::Challenger,
+                refresh_attempted_at: None,
                 created_at: Utc::now().into(),
                 updated_at: str_to_utc("2024-07-10"),
             },
@@ -330,6 +378,7 @@ mod test {
             wins: 10,
             losses: 5,
             rank_tier: RankTierEnum::Challenger,
+            refresh_attempted_at: None,
             created_at: Utc::now().into(),
             updated_at: str_to_utc("2024-07-10"),
         };
@@ -353,6 +402,7 @@ mod test {
             wins: 10,
             losses: 5,
             rank_tier: RankTierEnum::Challenger,
+            refresh_attempted_at: None,
             created_at: Utc::now().into(),
             updated_at: str_to_utc("2024-07-10"),
         };
@@ -380,6 +430,7 @@ mod test {
             wins: 10,
             losses: 5,
             rank_tier: RankTierEnum::Challenger,
+            refresh_attempted_at: None,
             created_at: Utc::now().into(),
             updated_at: str_to_utc("2024-07-10"),
         };
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Utc};
+use riven::consts::PlatformRoute;
+use sea_orm::ActiveValue::Set;
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, TransactionTrait,
+};
+use serde::Serialize;
+use tracing::{info, instrument};
+
+use crate::entities::{apex_tier_players, demotions, dodges, promotions};
+use crate::events::{self, Event};
+use crate::promotions_demotions::{self, DemotionHistory};
+use crate::snapshots::{self, LadderSnapshot};
+use crate::{config, db};
+
+/// The events detected in a single replayed snapshot, timestamped with when the snapshot was
+/// fetched.
+#[derive(Debug, Default)]
+pub struct Step {
+    pub dodges: Vec<dodges::ActiveModel>,
+    pub promotions: Vec<promotions::ActiveModel>,
+    pub demotions: Vec<demotions::ActiveModel>,
+}
+
+/// Feeds the snapshots of a region through dodge, promotion and demotion detection in memory,
+/// keeping the ladder and demotion history that the DB would otherwise hold.
+pub struct Replay {
+    region: PlatformRoute,
+    /// The ladder as stored after the previous snapshot, `None` before the first one.
+    players: Option<HashMap<String, apex_tier_players::Model>>,
+    demotions: DemotionHistory,
+}
+
+impl Replay {
+    pub fn new(region: PlatformRoute) -> Self {
+        Self {
+            region,
+            players: None,
+            demotions: HashMap::new(),
+        }
+    }
+
+    /// The ladder as stored after the last replayed snapshot.
+    pub fn players(&self) -> Option<&HashMap<String, apex_tier_players::Model>> {
+        self.players.as_ref()
+    }
+
+    /// Detects the events between the previous snapshot and this one. The first snapshot only
+    /// serves as the baseline, since the ladder before it is unknown.
+    pub async fn step(&mut self, snapshot: LadderSnapshot) -> Step {
+        let fetched_at = snapshot.fetched_at.fixed_offset();
+        let (api_players, _) = crate::apex_tier_players::from_leagues(
+            snapshot.master,
+            snapshot.grandmaster,
+            snapshot.challenger,
+        );
+
+        let Some(players) = &self.players else {
+            self.players = Some(crate::apex_tier_players::as_stored(
+                &api_players,
+                &HashMap::new(),
+                self.region,
+                fetched_at,
+            ));
+            return Step::default();
+        };
+
+        let mut step = Step {
+            dodges: crate::dodges::find_dodges(players, &api_players).await,
+            promotions: promotions_demotions::find_promotions(
+                &api_players,
+                players,
+                self.region,
+                &self.demotions,
+            ),
+            demotions: promotions_demotions::find_demotions(
+                &api_players,
+                players,
+                self.region,
+                &self.demotions,
+            ),
+        };
+        step.timestamp(fetched_at);
+
+        promotions_demotions::record_demotions(&mut self.demotions, &step.demotions, fetched_at);
+        self.players = Some(crate::apex_tier_players::as_stored(
+            &api_players,
+            players,
+            self.region,
+            fetched_at,
+        ));
+
+        step
+    }
+}
+
+impl Step {
+    fn timestamp(&mut self, at: DateTime<FixedOffset>) {
+        for dodge in &mut self.dodges {
+            dodge.created_at = Set(at);
+            dodge.updated_at = Set(at);
+        }
+        for promotion in &mut self.promotions {
+            promotion.created_at = Set(at);
+            promotion.updated_at = Set(at);
+        }
+        for demotion in &mut self.demotions {
+            demotion.created_at = Set(at);
+            demotion.updated_at = Set(at);
+        }
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        events::from_models(&self.dodges, &self.promotions, &self.demotions)
+    }
+}
+
+/// Where the differences between replayed and stored events are found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    OnlyReplayed,
+    OnlyStored,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    pub side: Side,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Matches replayed events against stored events, ignoring when they happened since stored events
+/// are timestamped when they were inserted rather than when the ladder was fetched.
+pub fn diff(
+    replayed: Vec<(DateTime<Utc>, Event)>,
+    stored: Vec<(DateTime<Utc>, Event)>,
+) -> Vec<Difference> {
+    let mut unmatched: HashMap<Event, Vec<DateTime<Utc>>> = HashMap::new();
+    for (at, event) in stored {
+        unmatched.entry(event).or_default().push(at);
+    }
+
+    let mut differences = vec![];
+    for (at, event) in replayed {
+        match unmatched.get_mut(&event) {
+            Some(times) if !times.is_empty() => {
+                times.remove(0);
+            }
+            _ => differences.push(Difference {
+                side: Side::OnlyReplayed,
+                at,
+                event,
+            }),
+        }
+    }
+    for (event, times) in unmatched {
+        for at in times {
+            differences.push(Difference {
+                side: Side::OnlyStored,
+                at,
+                event: event.clone(),
+            });
+        }
+    }
+
+    differences.sort_by_key(|difference| difference.at);
+    differences
+}
+
+async fn stored_events(
+    db: &DatabaseConnection,
+    region: PlatformRoute,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<(DateTime<Utc>, Event)>> {
+    let region = region.to_string();
+
+    let dodges = dodges::Entity::find()
+        .filter(dodges::Column::Region.eq(&region))
+        .filter(dodges::Column::CreatedAt.gte(from))
+        .filter(dodges::Column::CreatedAt.lt(to))
+        .order_by_asc(dodges::Column::CreatedAt)
+        .all(db)
+        .await?;
+    let promotions = promotions::Entity::find()
+        .filter(promotions::Column::Region.eq(&region))
+        .filter(promotions::Column::CreatedAt.gte(from))
+        .filter(promotions::Column::CreatedAt.lt(to))
+        .all(db)
+        .await?;
+    let demotions = demotions::Entity::find()
+        .filter(demotions::Column::Region.eq(&region))
+        .filter(demotions::Column::CreatedAt.gte(from))
+        .filter(demotions::Column::CreatedAt.lt(to))
+        .all(db)
+        .await?;
+
+    Ok(dodges
+        .iter()
+        .map(|dodge| (dodge.created_at.to_utc(), Event::from(dodge)))
+        .chain(
+            promotions
+                .iter()
+                .map(|promotion| (promotion.created_at.to_utc(), Event::from(promotion))),
+        )
+        .chain(
+            demotions
+                .iter()
+                .map(|demotion| (demotion.created_at.to_utc(), Event::from(demotion))),
+        )
+        .collect())
+}
+
+/// Writes the replayed events and the final ladder to a fresh DB.
+async fn write_to_db(
+    db: &DatabaseConnection,
+    steps: Vec<Step>,
+    players: Option<&HashMap<String, apex_tier_players::Model>>,
+) -> Result<()> {
+    let chunk_size = config::get().database.insert_chunk_size;
+    let txn = db.begin().await?;
+
+    let (mut all_dodges, mut all_promotions, mut all_demotions) = (vec![], vec![], vec![]);
+    for step in steps {
+        all_dodges.extend(step.dodges);
+        all_promotions.extend(step.promotions);
+        all_demotions.extend(step.demotions);
+    }
+    for chunk in all_dodges.chunks(chunk_size) {
+        dodges::Entity::insert_many(chunk.to_vec())
+            .exec(&txn)
+            .await?;
+    }
+    for chunk in all_promotions.chunks(chunk_size) {
+        promotions::Entity::insert_many(chunk.to_vec())
+            .exec(&txn)
+            .await?;
+    }
+    for chunk in all_demotions.chunks(chunk_size) {
+        demotions::Entity::insert_many(chunk.to_vec())
+            .exec(&txn)
+            .await?;
+    }
+
+    let player_models: Vec<apex_tier_players::ActiveModel> = players
+        .into_iter()
+        .flat_map(|players| players.values())
+        .map(|player| apex_tier_players::ActiveModel {
+            summoner_id: Set(player.summoner_id.clone()),
+            summoner_name: Set(player.summoner_name.clone()),
+            region: Set(player.region.clone()),
+            current_lp: Set(player.current_lp),
+            created_at: Set(player.created_at),
+            updated_at: Set(player.updated_at),
+            wins: Set(player.wins),
+            losses: Set(player.losses),
+            rank_tier: Set(player.rank_tier.clone()),
+            refresh_attempted_at: Set(player.refresh_attempted_at),
+        })
+        .collect();
+    for chunk in player_models.chunks(chunk_size) {
+        apex_tier_players::Entity::insert_many(chunk.to_vec())
+            .exec(&txn)
+            .await?;
+    }
+
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Replays the snapshots of a region fetched in `[from, to)`. The events are written to the fresh
+/// DB at `database_url` if one is given, otherwise they are compared against the stored events
+/// and the differences are written as JSON lines to `report`, or stdout.
+#[instrument(skip(database_url))]
+pub async fn run(
+    region: PlatformRoute,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    snapshot_dir: &Path,
+    database_url: Option<&str>,
+    report: Option<&Path>,
+) -> Result<()> {
+    let t1 = Instant::now();
+    let paths = snapshots::list(snapshot_dir, &region.to_string(), from, to)?;
+    info!(snapshots = paths.len(), "Replaying snapshots...");
+
+    let mut replay = Replay::new(region);
+    let mut steps = vec![];
+    let mut replayed = vec![];
+    for path in &paths {
+        let snapshot = snapshots::load(path)?;
+        let fetched_at = snapshot.fetched_at;
+        let step = replay.step(snapshot).await;
+        replayed.extend(step.events().into_iter().map(|event| (fetched_at, event)));
+        steps.push(step);
+    }
+
+    info!(
+        perf = t1.elapsed().as_millis(),
+        snapshots = paths.len(),
+        events = replayed.len(),
+        metric = "replay",
+        "Replayed snapshots."
+    );
+    println!(
+        "{}: replayed {} snapshots, {} events",
+        region,
+        paths.len(),
+        replayed.len()
+    );
+
+    if let Some(database_url) = database_url {
+        let target = db::connect(database_url).await?;
+        write_to_db(&target, steps, replay.players()).await?;
+        println!("{}: wrote replayed events to the target DB", region);
+        return Ok(());
+    }
+
+    let stored = stored_events(db::get_db().await?, region, from, to).await?;
+    let stored_count = stored.len();
+    let differences = diff(replayed, stored);
+
+    let mut writer: Box<dyn Write> = match report {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).with_context(|| format!("Failed to create {:?}", path))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    for difference in &differences {
+        writeln!(writer, "{}", serde_json::to_string(difference)?)?;
+    }
+    writer.flush()?;
+
+    let only_replayed = differences
+        .iter()
+        .filter(|difference| difference.side == Side::OnlyReplayed)
+        .count();
+    println!(
+        "{}: {} stored events, {} only replayed, {} only stored",
+        region,
+        stored_count,
+        only_replayed,
+        differences.len() - only_replayed
+    );
+
+    Ok(())
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use riven::consts::{Division, Tier};
+    use riven::models::league_v4::{LeagueItem, LeagueList};
+
+    use super::*;
+
+    fn item(summoner_id: &str, league_points: i32, wins: i32, losses: i32) -> LeagueItem {
+        LeagueItem {
+            summoner_id: summoner_id.to_string(),
+            league_points,
+            wins,
+            losses,
+            fresh_blood: false,
+            mini_series: None,
+            inactive: false,
+            veteran: false,
+            hot_streak: false,
+            rank: Division::I,
+        }
+    }
+
+    fn snapshot(minute: u32, master: Vec<LeagueItem>) -> LadderSnapshot {
+        let league = |tier, entries| LeagueList {
+            league_id: None,
+            entries,
+            tier,
+            name: None,
+            queue: None,
+        };
+        LadderSnapshot {
+            region: "EUW1".to_string(),
+            fetched_at: Utc.with_ymd_and_hms(2024, 7, 1, 12, minute, 0).unwrap(),
+            master: league(Tier::MASTER, master),
+            grandmaster: league(Tier::GRANDMASTER, vec![]),
+            challenger: league(Tier::CHALLENGER, vec![]),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_events_in_order() {
+        let mut replay = Replay::new(PlatformRoute::EUW1);
+
+        let baseline = replay
+            .step(snapshot(
+                0,
+                vec![item("a", 100, 10, 5), item("b", 20, 3, 3)],
+            ))
+            .await;
+        assert!(baseline.events().is_empty());
+
+        let dodge = replay
+            .step(snapshot(1, vec![item("a", 95, 10, 5), item("b", 20, 3, 3)]))
+            .await;
+        assert_eq!(
+            dodge.events(),
+            vec![Event::Dodge {
+                summoner_id: "a".to_string(),
+                rank_tier: "MASTER".to_string(),
+                lp_before: 100,
+                lp_after: 95,
+            }]
+        );
+
+        let demotion = replay.step(snapshot(2, vec![item("a", 95, 10, 5)])).await;
+        assert_eq!(
+            demotion.events(),
+            vec![Event::Demotion {
+                summoner_id: "b".to_string(),
+                at_wins: 3,
+                at_losses: 3,
+            }]
+        );
+
+        let unchanged = replay.step(snapshot(3, vec![item("a", 95, 10, 5)])).await;
+        assert!(unchanged.events().is_empty());
+
+        let promotion = replay
+            .step(snapshot(4, vec![item("a", 95, 10, 5), item("b", 0, 4, 3)]))
+            .await;
+        assert_eq!(
+            promotion.events(),
+            vec![Event::Promotion {
+                summoner_id: "b".to_string(),
+                at_wins: 4,
+                at_losses: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn diff_ignores_timestamps() {
+        let at = |minute| Utc.with_ymd_and_hms(2024, 7, 1, 12, minute, 0).unwrap();
+        let event = |summoner_id: &str| Event::Promotion {
+            summoner_id: summoner_id.to_string(),
+            at_wins: 1,
+            at_losses: 1,
+        };
+
+        let differences = diff(
+            vec![(at(0), event("a")), (at(1), event("b"))],
+            vec![(at(2), event("a")), (at(3), event("c"))],
+        );
+
+        assert_eq!(differences.len(), 2);
+        assert_eq!(differences[0].side, Side::OnlyReplayed);
+        assert_eq!(differences[0].event, event("b"));
+        assert_eq!(differences[1].side, Side::OnlyStored);
+        assert_eq!(differences[1].event, event("c"));
+    }
+}
@@ -1,12 +1,526 @@
-use std::env;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use dotenv::from_path;
+use anyhow::{bail, Context, Result};
 use lazy_static::lazy_static;
-use riven::RiotApi;
+use riven::consts::{PlatformRoute, QueueType};
+use riven::reqwest::header::{HeaderMap, HeaderValue};
+use riven::reqwest::{ClientBuilder, Proxy};
+use riven::{RiotApi, RiotApiConfig, RiotApiError};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::interval;
+use tracing::{error, info, instrument, warn};
+
+use crate::config::{self, Config, DEFAULT_KEY_NAME};
+use crate::util::with_timeout;
+
+/// How often the API key files are checked for changes.
+const KEY_FILE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a key that was still rate limited after riven's retries is passed over.
+const RATE_LIMITED_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// How often the usage of every key is logged.
+const USAGE_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Usable,
+    RateLimited {
+        until: Instant,
+    },
+    /// Rejected by the Riot API with 401 or 403 until the key is replaced.
+    Invalid,
+}
+
+impl fmt::Display for KeyState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyState::Usable => write!(f, "usable"),
+            KeyState::RateLimited { .. } => write!(f, "rate limited"),
+            KeyState::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
+struct Client {
+    api: Arc<RiotApi>,
+    key: String,
+    state: KeyState,
+}
+
+/// A key of the pool, with its own client and therefore its own rate limits.
+pub struct ApiKey {
+    pub name: String,
+    /// Regions the key is pinned to, or empty if it is shared by every region.
+    regions: Vec<PlatformRoute>,
+    /// File the key is read from, polled for changes.
+    file: Option<PathBuf>,
+    client: Mutex<Client>,
+    requests: AtomicU64,
+    rate_limited: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl ApiKey {
+    fn new(
+        name: &str,
+        regions: Vec<PlatformRoute>,
+        file: Option<PathBuf>,
+        key: &str,
+    ) -> Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            regions,
+            file,
+            client: Mutex::new(Client {
+                api: Arc::new(RiotApi::new(riot_api_config(config::get(), key)?)),
+                key: key.to_string(),
+                state: KeyState::Usable,
+            }),
+            requests: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    fn client(&self) -> std::sync::MutexGuard<'_, Client> {
+        self.client.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn serves(&self, region: PlatformRoute) -> bool {
+        self.regions.is_empty() || self.regions.contains(&region)
+    }
+
+    pub fn state(&self) -> KeyState {
+        let mut client = self.client();
+        if let KeyState::RateLimited { until } = client.state {
+            if Instant::now() >= until {
+                client.state = KeyState::Usable;
+            }
+        }
+        client.state
+    }
+
+    fn set_state(&self, state: KeyState) {
+        let mut client = self.client();
+        if client.state == KeyState::Invalid {
+            return;
+        }
+        client.state = state;
+    }
+
+    fn read_key(&self) -> Result<String> {
+        let config = &config::get().riot;
+        if self.name == DEFAULT_KEY_NAME {
+            return config.read_api_key();
+        }
+        match config.keys.iter().find(|key| key.name == self.name) {
+            Some(key) => key.read_api_key(),
+            None => Ok(self.client().key.clone()),
+        }
+    }
+
+    /// Reads the key again and swaps the client if the key changed. Returns whether it did.
+    fn reload(&self) -> Result<bool> {
+        let key = self.read_key()?;
+        if key.is_empty() {
+            bail!("Reloaded API key {} is empty", self.name);
+        }
+
+        let mut client = self.client();
+        if client.key == key {
+            return Ok(false);
+        }
+        client.api = Arc::new(RiotApi::new(riot_api_config(config::get(), &key)?));
+        client.key = key;
+        client.state = KeyState::Usable;
+        Ok(true)
+    }
+}
 
 lazy_static! {
-    pub static ref RIOT_API: RiotApi = {
-        from_path("../../.env").ok();
-        RiotApi::new(env::var("RIOT_API_KEY").expect("RIOT API KEY not set"))
+    /// Every configured key, pinned keys first. Keys were validated with the config, so a key that
+    /// still fails to build a client is left out rather than taking the backend down.
+    static ref POOL: Vec<ApiKey> = {
+        let riot = &config::get().riot;
+        let mut keys: Vec<Result<ApiKey>> = riot
+            .keys
+            .iter()
+            .map(|key| {
+                let regions = key
+                    .regions
+                    .iter()
+                    .filter_map(|region| PlatformRoute::from_str(region).ok())
+                    .collect();
+                ApiKey::new(&key.name, regions, key.api_key_file.clone(), &key.api_key)
+            })
+            .collect();
+        if !riot.api_key.is_empty() {
+            keys.push(ApiKey::new(
+                DEFAULT_KEY_NAME,
+                vec![],
+                riot.api_key_file.clone(),
+                &riot.api_key,
+            ));
+        }
+        let mut pool: Vec<ApiKey> = keys
+            .into_iter()
+            .filter_map(|key| {
+                key.map_err(|error| error!(?error, "Failed to create Riot API client. Skipping key."))
+                    .ok()
+            })
+            .collect();
+        pool.sort_by_key(|key| key.regions.is_empty());
+        pool
     };
+    /// Incremented every time a key changes.
+    static ref KEY_GENERATION: watch::Sender<u64> = watch::channel(0).0;
+}
+
+fn riot_api_config(config: &Config, api_key: &str) -> Result<RiotApiConfig> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        RiotApiConfig::RIOT_KEY_HEADER,
+        HeaderValue::from_str(api_key).context("Riot API key is not a valid header value")?,
+    );
+
+    let mut client_builder = ClientBuilder::new().default_headers(headers);
+    if let Some(proxy) = &config.http.proxy {
+        client_builder = client_builder.proxy(Proxy::all(proxy).context("Invalid proxy URL")?);
+    }
+
+    Ok(RiotApiConfig::with_client_builder(client_builder).set_base_url(&config.riot.base_url))
+}
+
+/// A client of the key picked for a region. Hold on to it for the duration of a batch of requests
+/// rather than picking a key per request.
+pub struct KeyHandle {
+    key: &'static ApiKey,
+    api: Arc<RiotApi>,
+}
+
+impl Deref for KeyHandle {
+    type Target = RiotApi;
+
+    fn deref(&self) -> &RiotApi {
+        &self.api
+    }
+}
+
+impl KeyHandle {
+    /// Accounts for requests made with the key, timed out ones included. Keys that were rate
+    /// limited or rejected are passed over by `for_region` afterwards.
+    pub fn record<'a, T: 'a>(
+        &self,
+        results: impl IntoIterator<Item = &'a Result<Result<T, RiotApiError>>>,
+    ) {
+        for result in results {
+            self.key.requests.fetch_add(1, Ordering::Relaxed);
+
+            let Ok(Err(error)) = result else {
+                continue;
+            };
+            match error.status_code().map(|status| status.as_u16()) {
+                Some(429) => {
+                    self.key.rate_limited.fetch_add(1, Ordering::Relaxed);
+                    if !matches!(self.key.state(), KeyState::RateLimited { .. }) {
+                        warn!(
+                            key = self.key.name,
+                            metric = "riot_api_key_rate_limited",
+                            "Riot API key is rate limited, failing over."
+                        );
+                    }
+                    self.key.set_state(KeyState::RateLimited {
+                        until: Instant::now() + RATE_LIMITED_COOLDOWN,
+                    });
+                }
+                Some(401 | 403) => {
+                    self.key.rejected.fetch_add(1, Ordering::Relaxed);
+                    if self.key.state() != KeyState::Invalid {
+                        error!(
+                            key = self.key.name,
+                            metric = "riot_api_key_invalid",
+                            "Riot API key was rejected."
+                        );
+                    }
+                    self.key.set_state(KeyState::Invalid);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Picks the key a region makes its requests with: a usable pinned key, else a usable shared key.
+/// Falls back to a rate limited key, and to an invalid one only if nothing else is left.
+pub fn for_region(region: PlatformRoute) -> KeyHandle {
+    let key = pick(&POOL, region).expect("Config validation ensures every region has a key");
+    KeyHandle {
+        key,
+        api: key.client().api.clone(),
+    }
+}
+
+fn pick(pool: &[ApiKey], region: PlatformRoute) -> Option<&ApiKey> {
+    let candidates = || pool.iter().filter(|key| key.serves(region));
+    candidates()
+        .find(|key| key.state() == KeyState::Usable)
+        .or_else(|| candidates().find(|key| key.state() != KeyState::Invalid))
+        .or_else(|| candidates().next())
+}
+
+/// Whether a region has a key that hasn't been rejected.
+pub fn has_valid_key(region: PlatformRoute) -> bool {
+    POOL.iter()
+        .any(|key| key.serves(region) && key.state() != KeyState::Invalid)
+}
+
+/// The generation of the current keys, to find out later whether one has been replaced.
+pub fn key_generation() -> u64 {
+    *KEY_GENERATION.borrow()
+}
+
+/// Completes once a key has been replaced since `generation`.
+pub async fn key_replaced_since(generation: u64) {
+    let mut receiver = KEY_GENERATION.subscribe();
+    // The sender lives in a static, so it is never dropped
+    let _ = receiver.wait_for(|current| *current != generation).await;
+}
+
+/// Whether the Riot API rejected a request of the error chain because of the API key.
+pub fn is_key_rejected(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<RiotApiError>()
+            .and_then(RiotApiError::status_code)
+            .is_some_and(|status| matches!(status.as_u16(), 401 | 403))
+    })
+}
+
+/// Reads every key again and swaps the clients of the keys that changed.
+fn reload_keys() {
+    let mut reloaded = false;
+    for key in POOL.iter() {
+        match key.reload() {
+            Ok(true) => {
+                info!(
+                    key = key.name,
+                    metric = "riot_api_key_reloaded",
+                    "Reloaded Riot API key."
+                );
+                reloaded = true;
+            }
+            Ok(false) => {}
+            Err(error) => error!(key = key.name, ?error, "Failed to reload Riot API key."),
+        }
+    }
+
+    if reloaded {
+        KEY_GENERATION.send_modify(|generation| *generation += 1);
+    } else {
+        info!("Riot API keys are unchanged.");
+    }
+}
+
+fn modified_at() -> Vec<Option<SystemTime>> {
+    POOL.iter()
+        .map(|key| {
+            key.file
+                .as_ref()
+                .and_then(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+        })
+        .collect()
+}
+
+/// Reloads the API keys on SIGHUP, and whenever one of the API key files changes.
+#[instrument(name = "api_key_reloader", skip_all)]
+pub async fn reload_keys_on_change() -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut last_modified = modified_at();
+    let mut polls = interval(KEY_FILE_POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => info!("Received SIGHUP, reloading Riot API keys..."),
+            _ = polls.tick() => {
+                let modified = modified_at();
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                info!("API key file changed, reloading Riot API keys...");
+            }
+        }
+
+        reload_keys();
+    }
+}
+
+/// The pairs of keys to compare, with a region both serve. Every key that can fail over to another
+/// is compared with it, directly or through other keys.
+fn comparisons(pool: &[ApiKey], regions: &[PlatformRoute]) -> Vec<(usize, usize, PlatformRoute)> {
+    // The keys already known to belong to the same application as each key
+    let mut groups: Vec<usize> = (0..pool.len()).collect();
+    let mut comparisons = vec![];
+    for &region in regions {
+        let mut candidates = (0..pool.len()).filter(|&i| pool[i].serves(region));
+        let Some(first) = candidates.next() else {
+            continue;
+        };
+        for other in candidates {
+            let (from, to) = (groups[other], groups[first]);
+            if from == to {
+                continue;
+            }
+            comparisons.push((first, other, region));
+            groups
+                .iter_mut()
+                .filter(|g| **g == from)
+                .for_each(|g| *g = to);
+        }
+    }
+    comparisons
+}
+
+/// The summoner IDs of the challenger ladder of `region`, as encrypted for the application of `key`.
+async fn challenger_ids(key: &'static ApiKey, region: PlatformRoute) -> Result<HashSet<String>> {
+    let handle = KeyHandle {
+        key,
+        api: key.client().api.clone(),
+    };
+    let result = with_timeout(
+        config::get().timeouts.league(),
+        handle
+            .league_v4()
+            .get_challenger_league(region, QueueType::RANKED_SOLO_5x5),
+    )
+    .await;
+    handle.record([&result]);
+    Ok(result??
+        .entries
+        .into_iter()
+        .map(|entry| entry.summoner_id)
+        .collect())
+}
+
+/// Checks that keys that fail over to each other belong to the same Riot application. Riot
+/// encrypts summoner IDs and PUUIDs per application, so IDs stored with one key can't be looked up
+/// with a key of another application. Returns the pairs of keys that don't, e.g. `kr and default`.
+pub async fn mismatched_applications() -> Result<Vec<String>> {
+    let pool: &'static [ApiKey] = &POOL;
+    let mut mismatched = vec![];
+    for (a, b, region) in comparisons(pool, &config::get().regions()) {
+        let (a, b): (&'static ApiKey, _) = (&pool[a], &pool[b]);
+        let (a_ids, b_ids) = (
+            challenger_ids(a, region).await?,
+            challenger_ids(b, region).await?,
+        );
+        // An empty ladder, e.g. at the start of a season, can't tell
+        if a_ids.is_empty() || b_ids.is_empty() {
+            warn!(
+                region = %region,
+                "Challenger ladder is empty, can't tell whether {} and {} belong to the same \
+                 application.",
+                a.name,
+                b.name
+            );
+            continue;
+        }
+        if a_ids.is_disjoint(&b_ids) {
+            mismatched.push(format!("{} and {}", a.name, b.name));
+        }
+    }
+    Ok(mismatched)
+}
+
+/// Periodically logs how many requests every key made and how often it was rate limited or
+/// rejected.
+#[instrument(name = "api_key_usage", skip_all)]
+pub async fn report_usage() {
+    let mut ticks = interval(USAGE_REPORT_INTERVAL);
+    // The first tick completes immediately
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        for key in POOL.iter() {
+            info!(
+                key = key.name,
+                state = %key.state(),
+                requests = key.requests.swap(0, Ordering::Relaxed),
+                rate_limited = key.rate_limited.swap(0, Ordering::Relaxed),
+                rejected = key.rejected.swap(0, Ordering::Relaxed),
+                metric = "riot_api_key_usage",
+                "Riot API key usage."
+            );
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool() -> Vec<ApiKey> {
+        vec![
+            ApiKey::new("kr", vec![PlatformRoute::KR], None, "RGAPI-kr").unwrap(),
+            ApiKey::new("shared", vec![], None, "RGAPI-shared").unwrap(),
+        ]
+    }
+
+    #[test]
+    fn pinned_keys_are_used_by_their_regions_only() {
+        let pool = pool();
+
+        assert_eq!(pick(&pool, PlatformRoute::KR).unwrap().name, "kr");
+        assert_eq!(pick(&pool, PlatformRoute::EUW1).unwrap().name, "shared");
+    }
+
+    #[test]
+    fn malformed_keys_fail_to_build_a_client() {
+        let error = ApiKey::new("kr", vec![], None, "RGAPI-kr\n").err().unwrap();
+        assert!(error.to_string().contains("not a valid header value"));
+    }
+
+    #[test]
+    fn fails_over_from_unusable_keys() {
+        let pool = pool();
+
+        pool[0].set_state(KeyState::RateLimited {
+            until: Instant::now() + RATE_LIMITED_COOLDOWN,
+        });
+        assert_eq!(pick(&pool, PlatformRoute::KR).unwrap().name, "shared");
+
+        pool[1].set_state(KeyState::Invalid);
+        assert_eq!(pick(&pool, PlatformRoute::KR).unwrap().name, "kr");
+        assert!(pool[1].state() == KeyState::Invalid);
+    }
+
+    #[test]
+    fn keys_that_fail_over_to_each_other_are_compared() {
+        let mut pool = pool();
+        pool.push(ApiKey::new("shared-2", vec![], None, "RGAPI-shared-2").unwrap());
+        pool.push(ApiKey::new("na", vec![PlatformRoute::NA1], None, "RGAPI-na").unwrap());
+
+        let comparisons = comparisons(&pool, &[PlatformRoute::KR, PlatformRoute::EUW1]);
+
+        // Both shared keys are compared with the KR key, so they aren't compared again for EUW1.
+        // The NA key isn't used by any region.
+        assert_eq!(
+            comparisons,
+            vec![(0, 1, PlatformRoute::KR), (0, 2, PlatformRoute::KR)]
+        );
+    }
 }
@@ -1,33 +1,238 @@
+use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
 use futures::future::join_all;
-use sea_orm::sea_query::OnConflict;
-use sea_orm::DatabaseTransaction;
+use riven::consts::PlatformRoute;
+use sea_orm::sea_query::{Expr, OnConflict};
 use sea_orm::{ActiveValue::Set, EntityTrait};
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, DatabaseTransaction, DbBackend, QueryFilter, Statement,
+};
 use tracing::{error, info, instrument};
+use unicode_normalization::UnicodeNormalization;
 
-use crate::config::INSERT_CHUNK_SIZE;
+use crate::circuit_breaker::Dependency;
+use crate::config;
+use crate::entities::riot_id_history;
 use crate::util::with_timeout;
-use crate::{entities::riot_ids, riot_api::RIOT_API};
+use crate::{entities::riot_ids, riot_api};
 
+/// The PUUID that used the Riot ID with the search names `$1` and `$2` most recently, preferring
+/// the account currently using it. Riot IDs that were stored before their history was recorded are
+/// only found in `riot_ids`.
+const FIND_PUUID: &str = r#"
+    SELECT puuid FROM (
+        SELECT puuid, valid_to FROM riot_id_history
+        WHERE lower_game_name = $1 AND lower_tag_line = $2
+        UNION ALL
+        SELECT puuid, NULL FROM riot_ids
+        WHERE lower_game_name = $1 AND lower_tag_line = $2
+    ) t
+    ORDER BY valid_to DESC NULLS FIRST
+    LIMIT 1
+"#;
+
+/// The stored Riot IDs of `puuids` that were updated within `ttl`.
+async fn fresh_riot_ids(
+    puuids: &[String],
+    ttl: Duration,
+    txn: &DatabaseTransaction,
+) -> Result<Vec<riot_ids::ActiveModel>> {
+    if ttl.is_zero() || puuids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    Ok(riot_ids::Entity::find()
+        .filter(riot_ids::Column::Puuid.is_in(puuids.iter().cloned()))
+        .filter(riot_ids::Column::UpdatedAt.gt(chrono::Utc::now() - ttl))
+        .all(txn)
+        .await?
+        .into_iter()
+        .map(|riot_id| riot_ids::ActiveModel {
+            puuid: Set(riot_id.puuid),
+            game_name: Set(riot_id.game_name),
+            tag_line: Set(riot_id.tag_line),
+            ..Default::default()
+        })
+        .collect())
+}
+
+/// Normalises a game name or tag line for case-insensitive search, as stored in `lower_game_name`
+/// and `lower_tag_line`. Composes accents first so that both encodings of a name match.
+pub fn search_name(name: &str) -> String {
+    name.nfc().collect::<String>().to_lowercase()
+}
+
+/// Works out how the Riot ID history changes with the looked up `accounts`: the IDs of the current
+/// entries that are replaced, and the entries to insert. Accounts that are stored without a
+/// history get their stored Riot ID recorded as well, valid since the account was stored.
+fn history_changes(
+    accounts: &[(&String, &String, &String)],
+    stored: &HashMap<String, riot_ids::Model>,
+    current: &HashMap<String, riot_id_history::Model>,
+    now: DateTime<FixedOffset>,
+) -> (Vec<i64>, Vec<riot_id_history::ActiveModel>) {
+    let entry = |puuid: &str, game_name: &str, tag_line: &str, valid_from, valid_to| {
+        riot_id_history::ActiveModel {
+            puuid: Set(puuid.to_string()),
+            game_name: Set(game_name.to_string()),
+            tag_line: Set(tag_line.to_string()),
+            valid_from: Set(valid_from),
+            valid_to: Set(valid_to),
+            lower_game_name: Set(Some(search_name(game_name))),
+            lower_tag_line: Set(Some(search_name(tag_line))),
+            ..Default::default()
+        }
+    };
+
+    let mut replaced = vec![];
+    let mut entries = vec![];
+    for &(puuid, game_name, tag_line) in accounts {
+        match (current.get(puuid), stored.get(puuid)) {
+            (Some(current), _)
+                if current.game_name == *game_name && current.tag_line == *tag_line => {}
+            (Some(current), _) => {
+                replaced.push(current.id);
+                entries.push(entry(puuid, game_name, tag_line, now, None));
+            }
+            (None, Some(stored))
+                if stored.game_name == *game_name && stored.tag_line == *tag_line =>
+            {
+                entries.push(entry(puuid, game_name, tag_line, stored.created_at, None));
+            }
+            (None, Some(stored)) => {
+                entries.push(entry(
+                    puuid,
+                    &stored.game_name,
+                    &stored.tag_line,
+                    stored.created_at,
+                    Some(now),
+                ));
+                entries.push(entry(puuid, game_name, tag_line, now, None));
+            }
+            (None, None) => entries.push(entry(puuid, game_name, tag_line, now, None)),
+        }
+    }
+
+    (replaced, entries)
+}
+
+/// Records the Riot IDs that changed in the history before they are overwritten in `riot_ids`.
+async fn record_history(
+    riot_id_models: &[riot_ids::ActiveModel],
+    txn: &DatabaseTransaction,
+) -> Result<()> {
+    let accounts: Vec<(&String, &String, &String)> = riot_id_models
+        .iter()
+        .filter_map(
+            |model| match (&model.puuid, &model.game_name, &model.tag_line) {
+                (Set(puuid), Set(game_name), Set(tag_line)) => Some((puuid, game_name, tag_line)),
+                _ => None,
+            },
+        )
+        .collect();
+    if accounts.is_empty() {
+        return Ok(());
+    }
+    let puuids = accounts.iter().map(|(puuid, _, _)| (*puuid).clone());
+
+    let stored = riot_ids::Entity::find()
+        .filter(riot_ids::Column::Puuid.is_in(puuids.clone()))
+        .all(txn)
+        .await?
+        .into_iter()
+        .map(|model| (model.puuid.clone(), model))
+        .collect();
+    let current = riot_id_history::Entity::find()
+        .filter(riot_id_history::Column::Puuid.is_in(puuids))
+        .filter(riot_id_history::Column::ValidTo.is_null())
+        .all(txn)
+        .await?
+        .into_iter()
+        .map(|model| (model.puuid.clone(), model))
+        .collect();
+
+    let now = chrono::Utc::now().fixed_offset();
+    let (replaced, entries) = history_changes(&accounts, &stored, &current, now);
+    if !replaced.is_empty() {
+        riot_id_history::Entity::update_many()
+            .col_expr(riot_id_history::Column::ValidTo, Expr::value(now))
+            .filter(riot_id_history::Column::Id.is_in(replaced.iter().copied()))
+            .exec(txn)
+            .await?;
+        info!(
+            changed = replaced.len(),
+            metric = "riot_ids_changed",
+            "Recorded changed Riot IDs."
+        );
+    }
+    for chunk in entries.chunks(config::get().database.insert_chunk_size) {
+        riot_id_history::Entity::insert_many(chunk.to_vec())
+            .exec(txn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Resolves a current or previous Riot ID to the PUUID of the account that used it most recently.
+/// Riot IDs are compared case-insensitively.
+pub async fn find_puuid(
+    game_name: &str,
+    tag_line: &str,
+    db: &impl ConnectionTrait,
+) -> Result<Option<String>> {
+    let row = db
+        .query_one(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            FIND_PUUID,
+            [search_name(game_name).into(), search_name(tag_line).into()],
+        ))
+        .await?;
+
+    Ok(row.map(|row| row.try_get("", "puuid")).transpose()?)
+}
+
+/// Looks up and stores the Riot IDs that aren't stored or are older than the freshness TTL,
+/// returning all Riot IDs of `puuids` that are now stored.
 #[instrument(skip_all, fields(puuids = puuids.len()))]
 pub async fn update_riot_ids(
     puuids: &[String],
+    region: PlatformRoute,
     txn: &DatabaseTransaction,
 ) -> Result<Vec<riot_ids::ActiveModel>> {
+    let fresh = fresh_riot_ids(puuids, config::get().freshness.account_ttl(), txn).await?;
+    let puuids: Vec<String> = puuids
+        .iter()
+        .filter(|puuid| !fresh.iter().any(|riot_id| riot_id.puuid.as_ref() == *puuid))
+        .cloned()
+        .collect();
+    info!(
+        hits = fresh.len(),
+        misses = puuids.len(),
+        metric = "account_cache",
+        "Checked for fresh Riot IDs."
+    );
+    if puuids.is_empty() {
+        return Ok(fresh);
+    }
+
     let t1 = Instant::now();
     info!("Getting account infos from Riot API...",);
 
+    let riot_api = riot_api::for_region(region);
     let results = join_all(puuids.iter().map(|puuid| {
         with_timeout(
-            Duration::from_secs(5),
-            RIOT_API
+            config::get().timeouts.account(),
+            riot_api
                 .account_v1()
                 .get_by_puuid(riven::consts::RegionalRoute::EUROPE, puuid),
         )
     }))
     .await;
+    riot_api.record(&results);
 
     info!(
         perf = t1.elapsed().as_millis(),
@@ -39,18 +244,20 @@ pub async fn update_riot_ids(
         .iter()
         .zip(results.iter())
         .filter_map(|(puuid, result)| match result.as_ref() {
-            Ok(Ok(a)) => {
-                if a.game_name.is_none() || a.tag_line.is_none() {
-                    error!(puuid, account = ?a, "Missing game_name or tag_line for puuid, skipping.");
-                    return None
-                }
-                Some(riot_ids::ActiveModel {
+            Ok(Ok(a)) => match (&a.game_name, &a.tag_line) {
+                (Some(game_name), Some(tag_line)) => Some(riot_ids::ActiveModel {
                     puuid: Set(a.puuid.clone()),
-                    game_name: Set(a.game_name.clone().unwrap()),
-                    tag_line: Set(a.tag_line.clone().unwrap()),
+                    game_name: Set(game_name.clone()),
+                    tag_line: Set(tag_line.clone()),
+                    lower_game_name: Set(Some(search_name(game_name))),
+                    lower_tag_line: Set(Some(search_name(tag_line))),
                     ..Default::default()
-                })
-            }
+                }),
+                _ => {
+                    error!(puuid, account = ?a, "Missing game_name or tag_line for puuid, skipping.");
+                    None
+                }
+            },
             Ok(Err(e)) => {
                 error!(puuid, error = ?e, "An account API query failed.");
                 None
@@ -62,21 +269,39 @@ pub async fn update_riot_ids(
         })
         .collect();
 
+    let all_failed = results.iter().all(|result| !matches!(result, Ok(Ok(_))));
+    if all_failed && !results.is_empty() {
+        return Err(anyhow!("All account API queries failed").context(Dependency::RiotCluster));
+    }
+
+    record_history(&riot_id_models, txn).await?;
+
     let t2 = Instant::now();
     info!(
         accounts = riot_id_models.len(),
         "Upserting accounts into DB..."
     );
 
-    for chunk in riot_id_models.chunks(INSERT_CHUNK_SIZE) {
+    for chunk in riot_id_models.chunks(config::get().database.insert_chunk_size) {
         riot_ids::Entity::insert_many(chunk.to_vec())
             .on_conflict(
                 OnConflict::column(riot_ids::Column::Puuid)
                     .update_columns([
                         riot_ids::Column::GameName,
                         riot_ids::Column::TagLine,
+                        riot_ids::Column::LowerGameName,
+                        riot_ids::Column::LowerTagLine,
                         riot_ids::Column::UpdatedAt,
                     ])
+                    // A renamed player may be found on lolpros under their new Riot ID
+                    .value(
+                        riot_ids::Column::LolprosCheckedAt,
+                        Expr::cust(
+                            "CASE WHEN riot_ids.game_name = EXCLUDED.game_name \
+                             AND riot_ids.tag_line = EXCLUDED.tag_line \
+                             THEN riot_ids.lolpros_checked_at END",
+                        ),
+                    )
                     .to_owned(),
             )
             .exec(txn)
@@ -90,5 +315,113 @@ pub async fn update_riot_ids(
         "Upserted accounts into DB."
     );
 
-    Ok(riot_id_models)
+    Ok(fresh.into_iter().chain(riot_id_models).collect())
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn stored(puuid: &str, game_name: &str, tag_line: &str) -> riot_ids::Model {
+        riot_ids::Model {
+            puuid: puuid.to_string(),
+            game_name: game_name.to_string(),
+            tag_line: tag_line.to_string(),
+            created_at: Utc::now().into(),
+            updated_at: Utc::now().into(),
+            lolpros_slug: None,
+            lolpros_checked_at: None,
+            lower_game_name: None,
+            lower_tag_line: None,
+            lolpros_synced: false,
+        }
+    }
+
+    fn current(id: i64, puuid: &str, game_name: &str, tag_line: &str) -> riot_id_history::Model {
+        riot_id_history::Model {
+            id,
+            puuid: puuid.to_string(),
+            game_name: game_name.to_string(),
+            tag_line: tag_line.to_string(),
+            valid_from: Utc::now().into(),
+            valid_to: None,
+            lower_game_name: Some(search_name(game_name)),
+            lower_tag_line: Some(search_name(tag_line)),
+        }
+    }
+
+    #[test]
+    fn search_names_are_lowercased_unicode() {
+        assert_eq!(search_name("Faker"), "faker");
+        assert_eq!(search_name("페이커"), "페이커");
+        assert_eq!(search_name("ÉLODIE"), "élodie");
+        // A decomposed É, as some keyboards produce it
+        assert_eq!(search_name("E\u{301}lodie"), "élodie");
+        assert_eq!(search_name("ΟΔΥΣΣΕΥΣ"), "οδυσσευς");
+        assert_eq!(search_name("ÄÖÜ"), "äöü");
+    }
+
+    #[test]
+    fn records_changed_riot_ids() {
+        let (a, b, c, d) = (
+            "a".to_string(),
+            "b".to_string(),
+            "c".to_string(),
+            "d".to_string(),
+        );
+        let (name, new_name, tag) = ("Name".to_string(), "New".to_string(), "EUW".to_string());
+        let stored = HashMap::from([
+            (b.clone(), stored("b", "Name", "EUW")),
+            (c.clone(), stored("c", "Name", "EUW")),
+            (d.clone(), stored("d", "Name", "EUW")),
+        ]);
+        let current = HashMap::from([
+            (c.clone(), current(1, "c", "Name", "EUW")),
+            (d.clone(), current(2, "d", "Name", "EUW")),
+        ]);
+        let now = Utc::now().fixed_offset();
+
+        let (replaced, entries) = history_changes(
+            &[
+                // New account
+                (&a, &name, &tag),
+                // Stored before the history was recorded, and renamed
+                (&b, &new_name, &tag),
+                // Unchanged
+                (&c, &name, &tag),
+                // Renamed
+                (&d, &new_name, &tag),
+            ],
+            &stored,
+            &current,
+            now,
+        );
+
+        assert_eq!(replaced, vec![2]);
+        assert_eq!(entries[2].lower_game_name, Set(Some("new".to_string())));
+        let entries: Vec<(&str, &str, bool)> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.puuid.as_ref().as_str(),
+                    entry.game_name.as_ref().as_str(),
+                    entry.valid_to.as_ref().is_none(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("a", "Name", true),
+                ("b", "Name", false),
+                ("b", "New", true),
+                ("d", "New", true),
+            ]
+        );
+    }
 }
@@ -0,0 +1,39 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::info;
+
+lazy_static! {
+    static ref SHUTDOWN: watch::Sender<bool> = watch::channel(false).0;
+}
+
+/// Asks every region to stop once its current cycle is done.
+pub fn request() {
+    SHUTDOWN.send_replace(true);
+}
+
+pub fn is_requested() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+/// Completes once a shutdown has been requested.
+pub async fn requested() {
+    let mut receiver = SHUTDOWN.subscribe();
+    // The sender lives in a static, so it is never dropped
+    let _ = receiver.wait_for(|requested| *requested).await;
+}
+
+/// Waits for SIGTERM or SIGINT and requests a shutdown when either arrives.
+pub async fn wait_for_signal() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down..."),
+        _ = sigint.recv() => info!("Received SIGINT, shutting down..."),
+    }
+
+    request();
+    Ok(())
+}
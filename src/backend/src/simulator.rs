@@ -0,0 +1,519 @@
+//! A simulated apex tier ladder that records what happened on it, so that the events detected by
+//! the pipeline can be checked against the ground truth.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use riven::consts::{Division, PlatformRoute, Tier};
+use riven::models::league_v4::{LeagueItem, LeagueList};
+
+use crate::apex_tier_players::LadderSource;
+use crate::config;
+use crate::events::Event;
+
+const GRANDMASTER_LP: i32 = 300;
+const CHALLENGER_LP: i32 = 700;
+const GAME_LP: i32 = 20;
+const DECAY_LP: i32 = 75;
+
+#[derive(Debug, Clone)]
+struct Player {
+    summoner_id: String,
+    league_points: i32,
+    wins: i32,
+    losses: i32,
+}
+
+impl Player {
+    fn tier(&self) -> Tier {
+        match self.league_points {
+            lp if lp >= CHALLENGER_LP => Tier::CHALLENGER,
+            lp if lp >= GRANDMASTER_LP => Tier::GRANDMASTER,
+            _ => Tier::MASTER,
+        }
+    }
+
+    fn demotion(&self) -> Happening {
+        Happening::Demotion {
+            summoner_id: self.summoner_id.clone(),
+            wins: self.wins,
+            losses: self.losses,
+        }
+    }
+}
+
+/// Something that happened on the simulated ladder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Happening {
+    Game {
+        summoner_id: String,
+        won: bool,
+    },
+    Dodge {
+        summoner_id: String,
+        tier: Tier,
+        lp_before: i32,
+        lp_after: i32,
+    },
+    Decay {
+        summoner_id: String,
+    },
+    Promotion {
+        summoner_id: String,
+        wins: i32,
+        losses: i32,
+    },
+    /// A player that dropped out of the apex tiers, along with their record when they were last on
+    /// the ladder.
+    Demotion {
+        summoner_id: String,
+        wins: i32,
+        losses: i32,
+    },
+    Ban {
+        summoner_id: String,
+        wins: i32,
+        losses: i32,
+    },
+}
+
+impl Happening {
+    /// The event the pipeline is expected to detect for this, if any. Banned players disappear
+    /// from the ladder just like demoted ones, so they are detected as demotions.
+    pub fn expected_event(&self) -> Option<Event> {
+        match self {
+            Happening::Game { .. } | Happening::Decay { .. } => None,
+            Happening::Dodge {
+                summoner_id,
+                tier,
+                lp_before,
+                lp_after,
+            } => Some(Event::Dodge {
+                summoner_id: summoner_id.clone(),
+                rank_tier: tier.to_string(),
+                lp_before: *lp_before as i64,
+                lp_after: *lp_after as i64,
+            }),
+            Happening::Promotion {
+                summoner_id,
+                wins,
+                losses,
+            } => Some(Event::Promotion {
+                summoner_id: summoner_id.clone(),
+                at_wins: *wins as i64,
+                at_losses: *losses as i64,
+            }),
+            Happening::Demotion {
+                summoner_id,
+                wins,
+                losses,
+            }
+            | Happening::Ban {
+                summoner_id,
+                wins,
+                losses,
+            } => Some(Event::Demotion {
+                summoner_id: summoner_id.clone(),
+                at_wins: *wins as i64,
+                at_losses: *losses as i64,
+            }),
+        }
+    }
+}
+
+/// Chances of what a player on the ladder does between two fetches.
+#[derive(Debug, Clone, Copy)]
+pub struct Activity {
+    pub game: f64,
+    pub dodge: f64,
+    pub decay: f64,
+    pub ban: f64,
+    /// Maximum amount of players entering the apex tiers between two fetches.
+    pub max_promotions: u32,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            game: 0.3,
+            dodge: 0.03,
+            decay: 0.01,
+            ban: 0.002,
+            max_promotions: 3,
+        }
+    }
+}
+
+/// An evolving ladder of a single region. Every player does at most one thing between two
+/// fetches, so that what happened can be told apart from the ladders alone.
+pub struct Simulator {
+    rng: StdRng,
+    activity: Activity,
+    next_id: u32,
+    ladder: BTreeMap<String, Player>,
+    /// Players that dropped out of the apex tiers and can be promoted again.
+    demoted: Vec<Player>,
+}
+
+impl Simulator {
+    pub fn new(seed: u64, players: u32, activity: Activity) -> Self {
+        let mut simulator = Self {
+            rng: StdRng::seed_from_u64(seed),
+            activity,
+            next_id: 0,
+            ladder: BTreeMap::new(),
+            demoted: vec![],
+        };
+        for _ in 0..players {
+            let mut player = simulator.new_player();
+            player.league_points = simulator.rng.gen_range(0..1200);
+            player.wins = simulator.rng.gen_range(50..400);
+            player.losses = simulator.rng.gen_range(50..400);
+            simulator.ladder.insert(player.summoner_id.clone(), player);
+        }
+        simulator
+    }
+
+    fn new_player(&mut self) -> Player {
+        self.next_id += 1;
+        Player {
+            summoner_id: format!("sim-{}", self.next_id),
+            league_points: 0,
+            wins: 0,
+            losses: 0,
+        }
+    }
+
+    fn play(&mut self, player: &mut Player) -> Option<Happening> {
+        let won = self.rng.gen_bool(0.5);
+        if won {
+            player.league_points += GAME_LP;
+            player.wins += 1;
+        } else if player.league_points < GAME_LP {
+            // The ladder shows the record from before the game that dropped them out of it
+            return Some(player.demotion());
+        } else {
+            player.league_points -= GAME_LP;
+            player.losses += 1;
+        }
+
+        Some(Happening::Game {
+            summoner_id: player.summoner_id.clone(),
+            won,
+        })
+    }
+
+    fn act(&mut self, player: &mut Player) -> Option<Happening> {
+        let dodge_lp_ceiling = config::get().detection.dodge_lp_ceiling;
+        let roll: f64 = self.rng.gen();
+        let activity = self.activity;
+
+        if roll < activity.ban {
+            return Some(Happening::Ban {
+                summoner_id: player.summoner_id.clone(),
+                wins: player.wins,
+                losses: player.losses,
+            });
+        }
+
+        let roll = roll - activity.ban;
+        if roll < activity.dodge {
+            let lp_lost = if self.rng.gen_bool(0.7) { 5 } else { 15 };
+            let lp_lost = lp_lost.min(dodge_lp_ceiling);
+            // Dodges can't take players below 0 LP
+            if player.league_points < lp_lost {
+                return None;
+            }
+
+            let lp_before = player.league_points;
+            player.league_points -= lp_lost;
+            return Some(Happening::Dodge {
+                summoner_id: player.summoner_id.clone(),
+                tier: player.tier(),
+                lp_before,
+                lp_after: player.league_points,
+            });
+        }
+
+        let roll = roll - activity.dodge;
+        if roll < activity.decay {
+            // Decay stops at 0 LP
+            if player.league_points < DECAY_LP {
+                return None;
+            }
+
+            player.league_points -= DECAY_LP;
+            return Some(Happening::Decay {
+                summoner_id: player.summoner_id.clone(),
+            });
+        }
+
+        let roll = roll - activity.decay;
+        if roll < activity.game {
+            return self.play(player);
+        }
+
+        None
+    }
+
+    /// Advances the ladder to the next fetch and returns what happened in between.
+    pub fn advance(&mut self) -> Vec<Happening> {
+        let mut happenings = vec![];
+        // A player demoted and promoted again between two fetches never leaves the ladder
+        let mut demoted = vec![];
+
+        let ladder = std::mem::take(&mut self.ladder);
+        for (summoner_id, mut player) in ladder {
+            match self.act(&mut player) {
+                Some(happening @ Happening::Demotion { .. }) => {
+                    happenings.push(happening);
+                    demoted.push(player);
+                }
+                Some(happening @ Happening::Ban { .. }) => happenings.push(happening),
+                happening => {
+                    happenings.extend(happening);
+                    self.ladder.insert(summoner_id, player);
+                }
+            }
+        }
+
+        for _ in 0..self.rng.gen_range(0..=self.activity.max_promotions) {
+            let mut player = if !self.demoted.is_empty() && self.rng.gen_bool(0.5) {
+                let index = self.rng.gen_range(0..self.demoted.len());
+                self.demoted.swap_remove(index)
+            } else {
+                self.new_player()
+            };
+            player.league_points = 0;
+            player.wins += 1;
+
+            happenings.push(Happening::Promotion {
+                summoner_id: player.summoner_id.clone(),
+                wins: player.wins,
+                losses: player.losses,
+            });
+            self.ladder.insert(player.summoner_id.clone(), player);
+        }
+        self.demoted.extend(demoted);
+
+        happenings
+    }
+
+    /// Removes every player from the apex tiers, as happens at the start of a season.
+    pub fn season_reset(&mut self) -> Vec<Happening> {
+        let ladder = std::mem::take(&mut self.ladder);
+        let happenings = ladder.values().map(Player::demotion).collect();
+        self.demoted.extend(ladder.into_values());
+        happenings
+    }
+
+    pub fn ladder(&self) -> (LeagueList, LeagueList, LeagueList) {
+        let league = |tier: Tier| LeagueList {
+            league_id: None,
+            entries: self
+                .ladder
+                .values()
+                .filter(|player| player.tier() == tier)
+                .map(|player| LeagueItem {
+                    summoner_id: player.summoner_id.clone(),
+                    league_points: player.league_points,
+                    wins: player.wins,
+                    losses: player.losses,
+                    fresh_blood: false,
+                    mini_series: None,
+                    inactive: false,
+                    veteran: false,
+                    hot_streak: false,
+                    rank: Division::I,
+                })
+                .collect(),
+            tier,
+            name: None,
+            queue: None,
+        };
+
+        (
+            league(Tier::MASTER),
+            league(Tier::GRANDMASTER),
+            league(Tier::CHALLENGER),
+        )
+    }
+}
+
+/// Serves the simulated ladder to the pipeline.
+#[derive(Clone)]
+pub struct SimulatedLadder(pub Arc<Mutex<Simulator>>);
+
+#[async_trait]
+impl LadderSource for SimulatedLadder {
+    async fn fetch(&self, _region: PlatformRoute) -> Result<(LeagueList, LeagueList, LeagueList)> {
+        Ok(self.0.lock().unwrap().ladder())
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+
+    use super::*;
+    use crate::circuit_breaker::Breakers;
+    use crate::config::StageToggles;
+    use crate::db;
+    use crate::entities::{apex_tier_players, demotions, dodges, player_counts, promotions};
+    use crate::pipeline::Pipeline;
+    use crate::replay::Replay;
+    use crate::snapshots::LadderSnapshot;
+    use crate::stages::{self, StageOptions};
+
+    /// Env var with the URL of a disposable Postgres DB that has the dodgetracker schema. The
+    /// end-to-end tests are skipped if it isn't set.
+    const TEST_DATABASE_URL_VAR: &str = "DODGETRACKER_TEST_DATABASE_URL";
+
+    /// A region that the end-to-end tests own in the test DB.
+    const TEST_REGION: PlatformRoute = PlatformRoute::TR1;
+
+    fn sorted(mut events: Vec<Event>) -> Vec<Event> {
+        events.sort_by_key(|event| format!("{:?}", event));
+        events
+    }
+
+    fn expected(happenings: &[Happening]) -> Vec<Event> {
+        sorted(
+            happenings
+                .iter()
+                .filter_map(Happening::expected_event)
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let run = || {
+            let mut simulator = Simulator::new(7, 200, Activity::default());
+            (0..10)
+                .flat_map(|_| simulator.advance())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(), run());
+    }
+
+    /// Checks the detection against the ground truth without a DB, through the replay engine.
+    #[tokio::test]
+    async fn replayed_events_match_ground_truth() {
+        let mut simulator = Simulator::new(1, 500, Activity::default());
+        let mut replay = Replay::new(TEST_REGION);
+        let snapshot = |simulator: &Simulator, minute: i64| {
+            let (master, grandmaster, challenger) = simulator.ladder();
+            LadderSnapshot {
+                region: TEST_REGION.to_string(),
+                fetched_at: chrono::DateTime::UNIX_EPOCH + chrono::Duration::minutes(minute),
+                master,
+                grandmaster,
+                challenger,
+            }
+        };
+
+        replay.step(snapshot(&simulator, 0)).await;
+        for tick in 1..50 {
+            let happenings = if tick == 25 {
+                simulator.season_reset()
+            } else {
+                simulator.advance()
+            };
+            let step = replay.step(snapshot(&simulator, tick)).await;
+
+            assert_eq!(
+                sorted(step.events()),
+                expected(&happenings),
+                "tick {}",
+                tick
+            );
+        }
+    }
+
+    async fn clear_region(db: &sea_orm::DatabaseConnection) {
+        let region = TEST_REGION.to_string();
+        apex_tier_players::Entity::delete_many()
+            .filter(apex_tier_players::Column::Region.eq(&region))
+            .exec(db)
+            .await
+            .unwrap();
+        dodges::Entity::delete_many()
+            .filter(dodges::Column::Region.eq(&region))
+            .exec(db)
+            .await
+            .unwrap();
+        promotions::Entity::delete_many()
+            .filter(promotions::Column::Region.eq(&region))
+            .exec(db)
+            .await
+            .unwrap();
+        demotions::Entity::delete_many()
+            .filter(demotions::Column::Region.eq(&region))
+            .exec(db)
+            .await
+            .unwrap();
+        player_counts::Entity::delete_many()
+            .filter(player_counts::Column::Region.eq(&region))
+            .exec(db)
+            .await
+            .unwrap();
+    }
+
+    /// Drives the region pipeline against Postgres and checks every cycle's events against the
+    /// ground truth. Run it with `cargo test -- --ignored` and `DODGETRACKER_TEST_DATABASE_URL` set to
+    /// a migrated database.
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DODGETRACKER_TEST_DATABASE_URL"]
+    async fn pipeline_events_match_ground_truth() {
+        let url = std::env::var(TEST_DATABASE_URL_VAR)
+            .unwrap_or_else(|_| panic!("{} is not set", TEST_DATABASE_URL_VAR));
+        let db = db::connect(&url).await.unwrap();
+        clear_region(&db).await;
+
+        let simulator = Arc::new(Mutex::new(Simulator::new(2, 300, Activity::default())));
+        let toggles = StageToggles {
+            enrichment: false,
+            lolpros: false,
+            ..Default::default()
+        };
+        let options = StageOptions {
+            ladder: Arc::new(SimulatedLadder(simulator.clone())),
+            shadow: None,
+        };
+        let pipeline = Pipeline::new(TEST_REGION, stages::build(toggles, options));
+        let mut breakers = Breakers::new(TEST_REGION);
+
+        // The first cycle stores the initial ladder and promotes everyone on it
+        pipeline.run_cycle(&db, &mut breakers).await.unwrap();
+
+        for tick in 1..30 {
+            let happenings = {
+                let mut simulator = simulator.lock().unwrap();
+                if tick == 15 {
+                    simulator.season_reset()
+                } else {
+                    simulator.advance()
+                }
+            };
+
+            let report = pipeline.run_cycle(&db, &mut breakers).await.unwrap();
+
+            assert_eq!(
+                sorted(crate::events::from_context(&report.ctx)),
+                expected(&happenings),
+                "tick {}",
+                tick
+            );
+        }
+
+        clear_region(&db).await;
+    }
+}
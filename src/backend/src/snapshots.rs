@@ -0,0 +1,267 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use riven::consts::PlatformRoute;
+use riven::models::league_v4::LeagueList;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument, warn};
+
+use crate::config::SnapshotConfig;
+
+const DAY_FORMAT: &str = "%Y-%m-%d";
+const FILE_FORMAT: &str = "%H%M%S%.3f";
+const EXTENSION: &str = "json.gz";
+
+/// The master, grandmaster and challenger ladders of a region as returned by the Riot API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LadderSnapshot {
+    pub region: String,
+    pub fetched_at: DateTime<Utc>,
+    pub master: LeagueList,
+    pub grandmaster: LeagueList,
+    pub challenger: LeagueList,
+}
+
+/// Snapshots are stored as `<dir>/<region>/<day>/<time>.json.gz`, so that old snapshots can be
+/// removed a day at a time.
+fn snapshot_path(dir: &Path, region: &str, fetched_at: DateTime<Utc>) -> PathBuf {
+    dir.join(region)
+        .join(fetched_at.format(DAY_FORMAT).to_string())
+        .join(format!("{}.{}", fetched_at.format(FILE_FORMAT), EXTENSION))
+}
+
+/// Writes a gzipped snapshot and returns its path.
+pub fn save(dir: &Path, snapshot: &LadderSnapshot) -> Result<PathBuf> {
+    let path = snapshot_path(dir, &snapshot.region, snapshot.fetched_at);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+    }
+
+    let file = File::create(&path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+    serde_json::to_writer(&mut encoder, snapshot)?;
+    encoder.finish()?;
+
+    Ok(path)
+}
+
+pub fn load(path: &Path) -> Result<LadderSnapshot> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    serde_json::from_reader(GzDecoder::new(BufReader::new(file)))
+        .with_context(|| format!("Failed to parse snapshot {:?}", path))
+}
+
+/// Finds the snapshots of a region fetched in `[from, to)`, oldest first.
+pub fn list(
+    dir: &Path,
+    region: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<PathBuf>> {
+    let region_dir = dir.join(region);
+    let mut paths = vec![];
+
+    let mut day = from.date_naive();
+    while day <= to.date_naive() {
+        let day_dir = region_dir.join(day.format(DAY_FORMAT).to_string());
+        day = day.succ_opt().context("Date out of range")?;
+        if !day_dir.exists() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&day_dir)? {
+            let path = entry?.path();
+            let fetched_at = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(&format!(".{}", EXTENSION)))
+                .and_then(|time| NaiveTime::parse_from_str(time, FILE_FORMAT).ok())
+                .zip(path.parent().and_then(|parent| parent.file_name()))
+                .and_then(|(time, day)| {
+                    let day = NaiveDate::parse_from_str(day.to_str()?, DAY_FORMAT).ok()?;
+                    Some(day.and_time(time).and_utc())
+                });
+
+            match fetched_at {
+                Some(fetched_at) if fetched_at >= from && fetched_at < to => {
+                    paths.push((fetched_at, path))
+                }
+                Some(_) => {}
+                None => warn!(?path, "Skipping file that isn't a snapshot."),
+            }
+        }
+    }
+
+    paths.sort();
+    Ok(paths.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Removes the days of snapshots of a region that are older than the retention period. Returns the
+/// amount of removed days.
+pub fn prune(dir: &Path, region: &str, retention_days: u32, now: DateTime<Utc>) -> Result<usize> {
+    let region_dir = dir.join(region);
+    if !region_dir.exists() {
+        return Ok(0);
+    }
+
+    let oldest_kept = now.date_naive() - chrono::Duration::days(retention_days as i64);
+    let mut removed = 0;
+    for entry in fs::read_dir(&region_dir)? {
+        let entry = entry?;
+        let day = entry.file_name();
+        let Some(day) = day
+            .to_str()
+            .and_then(|day| NaiveDate::parse_from_str(day, DAY_FORMAT).ok())
+        else {
+            continue;
+        };
+
+        if day < oldest_kept {
+            fs::remove_dir_all(entry.path())
+                .with_context(|| format!("Failed to remove {:?}", entry.path()))?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Saves a snapshot of the ladders just fetched for a region and prunes the region's expired
+/// snapshots. Runs on a blocking thread and only logs failures, so a full disk never fails a
+/// cycle.
+pub fn record(
+    config: &SnapshotConfig,
+    region: PlatformRoute,
+    master: &LeagueList,
+    grandmaster: &LeagueList,
+    challenger: &LeagueList,
+) {
+    let snapshot = LadderSnapshot {
+        region: region.to_string(),
+        fetched_at: Utc::now(),
+        master: master.clone(),
+        grandmaster: grandmaster.clone(),
+        challenger: challenger.clone(),
+    };
+    let dir = config.dir.clone();
+    let retention_days = config.retention_days;
+
+    tokio::task::spawn_blocking(move || record_blocking(&dir, retention_days, &snapshot));
+}
+
+#[instrument(name = "snapshot", skip_all, fields(region = snapshot.region))]
+fn record_blocking(dir: &Path, retention_days: u32, snapshot: &LadderSnapshot) {
+    let t1 = Instant::now();
+
+    match save(dir, snapshot) {
+        Ok(path) => info!(
+            perf = t1.elapsed().as_millis(),
+            path = ?path,
+            metric = "snapshot_saved",
+            "Saved ladder snapshot."
+        ),
+        Err(error) => error!(?error, "Failed to save ladder snapshot."),
+    }
+
+    match prune(dir, &snapshot.region, retention_days, snapshot.fetched_at) {
+        Ok(0) => {}
+        Ok(removed) => info!(days = removed, "Removed expired ladder snapshots."),
+        Err(error) => error!(?error, "Failed to remove expired ladder snapshots."),
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use riven::consts::Tier;
+
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "dodgetracker-snapshots-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn league(tier: Tier) -> LeagueList {
+        LeagueList {
+            league_id: None,
+            entries: vec![],
+            tier,
+            name: None,
+            queue: None,
+        }
+    }
+
+    fn snapshot(fetched_at: DateTime<Utc>) -> LadderSnapshot {
+        LadderSnapshot {
+            region: "EUW1".to_string(),
+            fetched_at,
+            master: league(Tier::MASTER),
+            grandmaster: league(Tier::GRANDMASTER),
+            challenger: league(Tier::CHALLENGER),
+        }
+    }
+
+    #[test]
+    fn saved_snapshots_can_be_loaded() {
+        let dir = test_dir("roundtrip");
+        let fetched_at = Utc.with_ymd_and_hms(2024, 7, 1, 12, 30, 0).unwrap();
+
+        let path = save(&dir, &snapshot(fetched_at)).unwrap();
+        let loaded = load(&path).unwrap();
+
+        assert_eq!(path, dir.join("EUW1/2024-07-01/123000.000.json.gz"));
+        assert_eq!(loaded.fetched_at, fetched_at);
+        assert_eq!(loaded.challenger.tier, Tier::CHALLENGER);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_snapshots_in_order() {
+        let dir = test_dir("list");
+        let start = Utc.with_ymd_and_hms(2024, 7, 1, 23, 59, 0).unwrap();
+        for minutes in [2, 0, 1, 10] {
+            save(&dir, &snapshot(start + chrono::Duration::minutes(minutes))).unwrap();
+        }
+
+        let paths = list(&dir, "EUW1", start, start + chrono::Duration::minutes(5)).unwrap();
+
+        assert_eq!(
+            paths,
+            vec![
+                dir.join("EUW1/2024-07-01/235900.000.json.gz"),
+                dir.join("EUW1/2024-07-02/000000.000.json.gz"),
+                dir.join("EUW1/2024-07-02/000100.000.json.gz"),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_expired_days() {
+        let dir = test_dir("prune");
+        let now = Utc.with_ymd_and_hms(2024, 7, 10, 0, 0, 0).unwrap();
+        save(&dir, &snapshot(now - chrono::Duration::days(8))).unwrap();
+        save(&dir, &snapshot(now - chrono::Duration::days(2))).unwrap();
+
+        assert_eq!(prune(&dir, "EUW1", 7, now).unwrap(), 1);
+        assert!(!dir.join("EUW1/2024-07-02").exists());
+        assert!(dir.join("EUW1/2024-07-08").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
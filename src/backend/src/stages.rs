@@ -0,0 +1,714 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use riven::consts::PlatformRoute;
+use sea_orm::ActiveValue::Set;
+use sea_orm::DatabaseTransaction;
+
+use crate::apex_tier_players::{LadderSource, RiotLadder};
+use crate::circuit_breaker::Dependency;
+use crate::config::{self, StageToggles};
+use crate::entities::apex_tier_players::Model as StoredPlayer;
+use crate::entities::demotions;
+use crate::pipeline::{CycleContext, ErrorPolicy, Stage};
+use crate::pro_players::ProPlayerSource;
+use crate::promotions_demotions::DemotionHistory;
+use crate::{
+    apex_tier_players, dodges, enrichment_jobs, player_counts, pro_players, promotions_demotions,
+    riot_ids, summoners,
+};
+
+/// Fetches the master, grandmaster and challenger ladders, from the Riot API unless the pipeline
+/// is given another source.
+pub struct FetchLadder {
+    source: Arc<dyn LadderSource>,
+}
+
+#[async_trait]
+impl Stage for FetchLadder {
+    fn name(&self) -> &'static str {
+        "fetch_ladder"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_fetch_ladder"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::Retry {
+            attempts: 1,
+            delay: Duration::from_millis(500),
+        }
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::RiotPlatform)
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, _txn: &DatabaseTransaction) -> Result<()> {
+        let (api_players, tier_counts) =
+            apex_tier_players::get_players_from_api(self.source.as_ref(), ctx.region).await?;
+        ctx.api_players = api_players;
+        ctx.tier_counts = tier_counts;
+        ctx.api_requests += 3;
+        Ok(())
+    }
+}
+
+/// The ladder and demotions as seen by the previous cycles of a shadow pipeline. Shadow cycles are
+/// rolled back, so without it every cycle would compare against the same stored ladder and demote
+/// the players that left it again.
+#[derive(Debug, Clone, Default)]
+pub struct ShadowLadder {
+    players: Arc<Mutex<Option<HashMap<String, StoredPlayer>>>>,
+    demotions: Arc<Mutex<DemotionHistory>>,
+}
+
+impl ShadowLadder {
+    fn get(&self) -> Option<HashMap<String, StoredPlayer>> {
+        self.players
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn set(&self, players: HashMap<String, StoredPlayer>) {
+        *self.players.lock().unwrap_or_else(|e| e.into_inner()) = Some(players);
+    }
+
+    fn demotions(&self) -> DemotionHistory {
+        self.demotions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn record_demotions(&self, demotions: &[demotions::ActiveModel]) {
+        let mut history = self.demotions.lock().unwrap_or_else(|e| e.into_inner());
+        promotions_demotions::record_demotions(
+            &mut history,
+            demotions,
+            chrono::Utc::now().fixed_offset(),
+        );
+    }
+
+    /// Carries the ladder and demotions of a cycle over to the next cycles. Called once the cycle
+    /// has run every stage, so that a failed cycle leaves the shadow ladder as it was.
+    pub fn apply(&self, ctx: &CycleContext) {
+        self.set(apex_tier_players::as_stored(
+            &ctx.api_players,
+            &ctx.db_players,
+            ctx.region,
+            chrono::Utc::now().fixed_offset(),
+        ));
+        self.record_demotions(&ctx.demotions);
+    }
+}
+
+/// Demotions that shadow pipelines made in earlier cycles, which were rolled back.
+fn unstored_demotions(shadow: &Option<ShadowLadder>) -> DemotionHistory {
+    shadow
+        .as_ref()
+        .map(ShadowLadder::demotions)
+        .unwrap_or_default()
+}
+
+/// Loads the ladder as it was stored in the previous cycle.
+pub struct LoadStoredPlayers {
+    shadow: Option<ShadowLadder>,
+}
+
+#[async_trait]
+impl Stage for LoadStoredPlayers {
+    fn name(&self) -> &'static str {
+        "load_stored_players"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_load_stored_players"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::AbortCycle
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::Postgres)
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        ctx.db_players = apex_tier_players::get_players_from_db(txn, ctx.region).await?;
+        if let Some(players) = self.shadow.as_ref().and_then(ShadowLadder::get) {
+            ctx.db_players = players;
+        }
+        Ok(())
+    }
+}
+
+pub struct DetectDodges;
+
+#[async_trait]
+impl Stage for DetectDodges {
+    fn name(&self) -> &'static str {
+        "detect_dodges"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_detect_dodges"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::AbortCycle
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        None
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, _txn: &DatabaseTransaction) -> Result<()> {
+        ctx.dodges = dodges::find_dodges(&ctx.db_players, &ctx.api_players).await;
+        Ok(())
+    }
+}
+
+/// Looks up and stores the summoners of the players that dodged.
+/// Lookups that fail are queued by [`EnqueueEnrichment`] and retried by the enrichment worker.
+pub struct EnrichSummoners;
+
+#[async_trait]
+impl Stage for EnrichSummoners {
+    fn name(&self) -> &'static str {
+        "enrich_summoners"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_enrich_summoners"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::Ignore
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::RiotPlatform)
+    }
+
+    fn should_run(&self, ctx: &CycleContext) -> bool {
+        !ctx.dodges.is_empty()
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        let summoner_ids: Vec<&str> = ctx
+            .dodges
+            .iter()
+            .filter_map(|dodge| match &dodge.summoner_id {
+                Set(id) => Some(id.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        ctx.api_requests += summoner_ids.len() as u32;
+        ctx.puuids = summoners::upsert_summoners(&summoner_ids, ctx.region, txn).await?;
+        Ok(())
+    }
+}
+
+/// Looks up and stores the Riot IDs of the players that dodged.
+/// Lookups that fail are queued by [`EnqueueEnrichment`] and retried by the enrichment worker.
+pub struct EnrichRiotIds;
+
+#[async_trait]
+impl Stage for EnrichRiotIds {
+    fn name(&self) -> &'static str {
+        "enrich_riot_ids"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_enrich_riot_ids"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::Ignore
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::RiotCluster)
+    }
+
+    fn should_run(&self, ctx: &CycleContext) -> bool {
+        !ctx.puuids.is_empty()
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        ctx.riot_id_models = riot_ids::update_riot_ids(&ctx.puuids, ctx.region, txn).await?;
+        Ok(())
+    }
+}
+
+/// Tags the players that dodged that a source knows to be pros.
+pub struct ProPlayerEnrichment {
+    source: Arc<dyn ProPlayerSource>,
+}
+
+#[async_trait]
+impl Stage for ProPlayerEnrichment {
+    fn name(&self) -> &'static str {
+        self.source.stage_name()
+    }
+
+    fn metric(&self) -> &'static str {
+        self.source.metric()
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::Ignore
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        self.source.dependency()
+    }
+
+    fn should_run(&self, ctx: &CycleContext) -> bool {
+        !ctx.riot_id_models.is_empty()
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        self.source.tag(ctx.region, &ctx.riot_id_models, txn).await
+    }
+}
+
+pub struct InsertDodges;
+
+#[async_trait]
+impl Stage for InsertDodges {
+    fn name(&self) -> &'static str {
+        "insert_dodges"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_insert_dodges"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::AbortCycle
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::Postgres)
+    }
+
+    fn should_run(&self, ctx: &CycleContext) -> bool {
+        !ctx.dodges.is_empty()
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        ctx.dodge_ids = dodges::insert_dodges(&ctx.dodges, txn).await?;
+        Ok(())
+    }
+}
+
+/// Queues the inserted dodges that couldn't be enriched for the enrichment worker.
+pub struct EnqueueEnrichment;
+
+#[async_trait]
+impl Stage for EnqueueEnrichment {
+    fn name(&self) -> &'static str {
+        "enqueue_enrichment"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_enqueue_enrichment"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::AbortCycle
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::Postgres)
+    }
+
+    fn should_run(&self, ctx: &CycleContext) -> bool {
+        !ctx.dodge_ids.is_empty()
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        enrichment_jobs::enqueue_unenriched(&ctx.dodge_ids, txn).await?;
+        Ok(())
+    }
+}
+
+/// Stores the fetched ladder so that it can be compared against in the next cycle.
+pub struct UpsertPlayers;
+
+#[async_trait]
+impl Stage for UpsertPlayers {
+    fn name(&self) -> &'static str {
+        "upsert_players"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_upsert_players"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::AbortCycle
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::Postgres)
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        apex_tier_players::upsert_players(&ctx.api_players, ctx.region, txn).await?;
+        Ok(())
+    }
+}
+
+pub struct InsertPromotions {
+    shadow: Option<ShadowLadder>,
+}
+
+#[async_trait]
+impl Stage for InsertPromotions {
+    fn name(&self) -> &'static str {
+        "insert_promotions"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_insert_promotions"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::AbortCycle
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::Postgres)
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        ctx.promotions = promotions_demotions::insert_promotions(
+            &ctx.api_players,
+            &ctx.db_players,
+            ctx.region,
+            &unstored_demotions(&self.shadow),
+            txn,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+pub struct InsertDemotions {
+    shadow: Option<ShadowLadder>,
+}
+
+#[async_trait]
+impl Stage for InsertDemotions {
+    fn name(&self) -> &'static str {
+        "insert_demotions"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_insert_demotions"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::AbortCycle
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::Postgres)
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        ctx.demotions = promotions_demotions::insert_demotions(
+            &ctx.api_players,
+            &ctx.db_players,
+            ctx.region,
+            &unstored_demotions(&self.shadow),
+            txn,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+pub struct UpdatePlayerCounts;
+
+#[async_trait]
+impl Stage for UpdatePlayerCounts {
+    fn name(&self) -> &'static str {
+        "update_player_counts"
+    }
+
+    fn metric(&self) -> &'static str {
+        "stage_update_player_counts"
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::Ignore
+    }
+
+    fn dependency(&self) -> Option<Dependency> {
+        Some(Dependency::Postgres)
+    }
+
+    async fn run(&self, ctx: &mut CycleContext, txn: &DatabaseTransaction) -> Result<()> {
+        let (master_count, grandmaster_count, challenger_count) = ctx.tier_counts;
+        player_counts::update_player_counts(
+            master_count,
+            grandmaster_count,
+            challenger_count,
+            ctx.region,
+            txn,
+        )
+        .await
+    }
+}
+
+/// How the stages of a pipeline get their data.
+#[derive(Clone)]
+pub struct StageOptions {
+    pub ladder: Arc<dyn LadderSource>,
+    /// Set for shadow pipelines, which carry the ladder over between cycles in it.
+    pub shadow: Option<ShadowLadder>,
+}
+
+impl Default for StageOptions {
+    fn default() -> Self {
+        Self {
+            ladder: Arc::new(RiotLadder),
+            shadow: None,
+        }
+    }
+}
+
+/// Builds the stages of a region's pipeline, leaving out the stages that are turned off for the
+/// region.
+pub fn for_region(region: PlatformRoute, options: StageOptions) -> Vec<Box<dyn Stage>> {
+    let toggles = config::get()
+        .region(region)
+        .map(|region| region.stages)
+        .unwrap_or_default();
+
+    build(toggles, options)
+}
+
+pub fn build(toggles: StageToggles, options: StageOptions) -> Vec<Box<dyn Stage>> {
+    let StageOptions { ladder, shadow } = options;
+
+    let mut stages: Vec<Box<dyn Stage>> = vec![
+        Box::new(FetchLadder { source: ladder }),
+        Box::new(LoadStoredPlayers {
+            shadow: shadow.clone(),
+        }),
+        Box::new(DetectDodges),
+    ];
+    // Dodgers are enriched before their dodges are inserted so that the insert notifications
+    // include them, and the lookups that failed are queued for the inserted dodges
+    if toggles.enrichment {
+        stages.push(Box::new(EnrichSummoners));
+        stages.push(Box::new(EnrichRiotIds));
+        for source in pro_players::sources(toggles) {
+            stages.push(Box::new(ProPlayerEnrichment { source }));
+        }
+        stages.push(Box::new(InsertDodges));
+        stages.push(Box::new(EnqueueEnrichment));
+    } else {
+        stages.push(Box::new(InsertDodges));
+    }
+    stages.push(Box::new(UpsertPlayers));
+    if toggles.tier_changes {
+        stages.push(Box::new(InsertPromotions {
+            shadow: shadow.clone(),
+        }));
+        stages.push(Box::new(InsertDemotions { shadow }));
+    }
+    if toggles.player_counts {
+        stages.push(Box::new(UpdatePlayerCounts));
+    }
+
+    stages
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use riven::consts::Division;
+    use riven::models::league_v4::LeagueItem;
+    use sea_orm::{DatabaseBackend, MockDatabase, TransactionTrait};
+
+    use super::*;
+    use crate::entities::sea_orm_active_enums::RankTierEnum;
+    use crate::entities::{apex_tier_players, promotions};
+
+    fn league_item(summoner_id: &str, league_points: i32, wins: i32, losses: i32) -> LeagueItem {
+        LeagueItem {
+            summoner_id: summoner_id.to_string(),
+            league_points,
+            wins,
+            losses,
+            fresh_blood: false,
+            mini_series: None,
+            inactive: false,
+            veteran: false,
+            hot_streak: false,
+            rank: Division::I,
+        }
+    }
+
+    #[tokio::test]
+    async fn detect_dodges_fills_context() {
+        let db = MockDatabase::new(DatabaseBackend::Postgres).into_connection();
+        let txn = db.begin().await.unwrap();
+        let mut ctx = CycleContext::new(PlatformRoute::EUW1);
+
+        ctx.db_players.insert(
+            "a".to_string(),
+            apex_tier_players::Model {
+                summoner_id: "a".to_string(),
+                summoner_name: None,
+                region: PlatformRoute::EUW1.to_string(),
+                current_lp: 100,
+                wins: 10,
+                losses: 5,
+                rank_tier: RankTierEnum::Challenger,
+                refresh_attempted_at: None,
+                created_at: Utc::now().into(),
+                updated_at: Utc::now().into(),
+            },
+        );
+        ctx.api_players.insert(
+            "a".to_string(),
+            (league_item("a", 90, 10, 5), RankTierEnum::Challenger),
+        );
+
+        DetectDodges.run(&mut ctx, &txn).await.unwrap();
+
+        assert_eq!(ctx.dodges.len(), 1);
+        assert!(EnrichSummoners.should_run(&ctx));
+        assert!(InsertDodges.should_run(&ctx));
+        assert!(!EnrichRiotIds.should_run(&ctx));
+    }
+
+    #[tokio::test]
+    async fn shadow_ladder_carries_over() {
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([Vec::<apex_tier_players::Model>::new()])
+            .into_connection();
+        let txn = db.begin().await.unwrap();
+        let shadow = ShadowLadder::default();
+
+        let mut ctx = CycleContext::new(PlatformRoute::EUW1);
+        ctx.api_players.insert(
+            "a".to_string(),
+            (league_item("a", 90, 10, 5), RankTierEnum::Master),
+        );
+        shadow.apply(&ctx);
+
+        let mut next = CycleContext::new(PlatformRoute::EUW1);
+        LoadStoredPlayers {
+            shadow: Some(shadow),
+        }
+        .run(&mut next, &txn)
+        .await
+        .unwrap();
+
+        assert_eq!(next.db_players["a"].current_lp, 90);
+    }
+
+    #[tokio::test]
+    async fn shadow_demotions_carry_over() {
+        let now = Utc::now().fixed_offset();
+        let demotion = demotions::Model {
+            demotion_id: 1,
+            summoner_id: "a".to_string(),
+            region: PlatformRoute::EUW1.to_string(),
+            created_at: now,
+            updated_at: now,
+            at_wins: 10,
+            at_losses: 5,
+        };
+        let promotion = promotions::Model {
+            promotion_id: 1,
+            summoner_id: "a".to_string(),
+            region: PlatformRoute::EUW1.to_string(),
+            created_at: now,
+            updated_at: now,
+            at_wins: 12,
+            at_losses: 5,
+        };
+        // The stored demotions and the inserted rows, in the order the stages query them
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([vec![], vec![demotion], vec![], vec![]])
+            .append_query_results([vec![promotion]])
+            .into_connection();
+        let txn = db.begin().await.unwrap();
+        let shadow = ShadowLadder::default();
+        let demote = InsertDemotions {
+            shadow: Some(shadow.clone()),
+        };
+        let promote = InsertPromotions {
+            shadow: Some(shadow.clone()),
+        };
+
+        // The player left the ladder after it was stored, a day ago
+        let departed = apex_tier_players::Model {
+            summoner_id: "a".to_string(),
+            summoner_name: None,
+            region: PlatformRoute::EUW1.to_string(),
+            current_lp: 0,
+            wins: 10,
+            losses: 5,
+            rank_tier: RankTierEnum::Master,
+            refresh_attempted_at: None,
+            created_at: (Utc::now() - chrono::Duration::days(1)).into(),
+            updated_at: (Utc::now() - chrono::Duration::days(1)).into(),
+        };
+        let cycle = || {
+            let mut ctx = CycleContext::new(PlatformRoute::EUW1);
+            ctx.db_players.insert("a".to_string(), departed.clone());
+            ctx
+        };
+
+        let mut first = cycle();
+        demote.run(&mut first, &txn).await.unwrap();
+        assert_eq!(first.demotions.len(), 1);
+        shadow.apply(&first);
+
+        // The demotion was rolled back, but the shadow ladder remembers it
+        let mut second = cycle();
+        demote.run(&mut second, &txn).await.unwrap();
+        assert!(second.demotions.is_empty());
+
+        let mut third = cycle();
+        third.api_players.insert(
+            "a".to_string(),
+            (league_item("a", 0, 12, 5), RankTierEnum::Master),
+        );
+        promote.run(&mut third, &txn).await.unwrap();
+        assert_eq!(third.promotions.len(), 1);
+    }
+
+    #[test]
+    fn toggles_remove_stages() {
+        let names = |region| -> Vec<&'static str> {
+            for_region(region, StageOptions::default())
+                .iter()
+                .map(|stage| stage.name())
+                .collect()
+        };
+
+        assert!(names(PlatformRoute::EUW1).contains(&"lolpros_enrichment"));
+        assert!(!names(PlatformRoute::KR).contains(&"lolpros_enrichment"));
+        assert!(names(PlatformRoute::KR).contains(&"insert_promotions"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use riven::consts::PlatformRoute;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionState {
+    Starting,
+    Running,
+    /// The region is paused or running without some of its dependencies, with the reason why.
+    Degraded(String),
+    /// The Riot API rejected the API key, so the region is paused until the key is replaced.
+    ApiKeyInvalid,
+}
+
+impl fmt::Display for RegionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegionState::Starting => write!(f, "starting"),
+            RegionState::Running => write!(f, "running"),
+            RegionState::Degraded(reason) => write!(f, "degraded ({})", reason),
+            RegionState::ApiKeyInvalid => write!(f, "degraded (API key invalid)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionStatus {
+    pub state: RegionState,
+    /// Amount of times the region's task has been restarted by the supervisor.
+    pub restarts: u32,
+    pub last_cycle: Option<Instant>,
+}
+
+impl Default for RegionStatus {
+    fn default() -> Self {
+        RegionStatus {
+            state: RegionState::Starting,
+            restarts: 0,
+            last_cycle: None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref STATUSES: Mutex<HashMap<PlatformRoute, RegionStatus>> = Mutex::new(HashMap::new());
+}
+
+fn with_status<T>(region: PlatformRoute, f: impl FnOnce(&mut RegionStatus) -> T) -> T {
+    let mut statuses = STATUSES.lock().unwrap_or_else(|e| e.into_inner());
+    let status = statuses.entry(region).or_default();
+    f(status)
+}
+
+/// Sets the state of a region, logging the change if it differs from the previous state.
+pub fn set_state(region: PlatformRoute, state: RegionState) {
+    with_status(region, |status| {
+        if status.state == state {
+            return;
+        }
+
+        match &state {
+            RegionState::Degraded(_) | RegionState::ApiKeyInvalid => {
+                warn!(%region, from = %status.state, to = %state, "Region status changed.")
+            }
+            _ => info!(%region, from = %status.state, to = %state, "Region status changed."),
+        }
+        status.state = state;
+    });
+}
+
+/// Counts a restart of the region's task and returns the total amount of restarts.
+pub fn record_restart(region: PlatformRoute) -> u32 {
+    with_status(region, |status| {
+        status.restarts += 1;
+        status.restarts
+    })
+}
+
+/// Marks that a region has committed a cycle.
+pub fn record_cycle(region: PlatformRoute) {
+    with_status(region, |status| status.last_cycle = Some(Instant::now()));
+}
+
+/// Returns the status of every region that has reported one.
+pub fn all() -> Vec<(PlatformRoute, RegionStatus)> {
+    let statuses = STATUSES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut all: Vec<(PlatformRoute, RegionStatus)> = statuses
+        .iter()
+        .map(|(region, status)| (*region, status.clone()))
+        .collect();
+    all.sort_by_key(|(region, _)| region.to_string());
+    all
+}
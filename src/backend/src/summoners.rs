@@ -1,36 +1,182 @@
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
 use futures::future::join_all;
 use riven::consts::PlatformRoute;
-use sea_orm::sea_query::OnConflict;
-use sea_orm::DatabaseTransaction;
+use sea_orm::sea_query::{Expr, OnConflict};
 use sea_orm::{ActiveValue::Set, EntityTrait};
+use sea_orm::{ColumnTrait, DatabaseTransaction, QueryFilter, QuerySelect};
 use tracing::{error, info, instrument};
 
 use crate::util::with_timeout;
 use crate::{
-    config::INSERT_CHUNK_SIZE,
-    entities::{self, summoners},
-    riot_api::RIOT_API,
+    circuit_breaker::Dependency,
+    config,
+    entities::{self, summoner_history, summoners},
+    riot_api,
 };
 
+/// PUUIDs of the stored summoners that were updated within `ttl`, keyed by summoner ID.
+async fn fresh_summoners(
+    summoner_ids: &[&str],
+    region: PlatformRoute,
+    ttl: Duration,
+    txn: &DatabaseTransaction,
+) -> Result<HashMap<String, String>> {
+    if ttl.is_zero() || summoner_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    Ok(summoners::Entity::find()
+        .filter(summoners::Column::Region.eq(region.to_string()))
+        .filter(summoners::Column::SummonerId.is_in(summoner_ids.iter().copied()))
+        .filter(summoners::Column::UpdatedAt.gt(chrono::Utc::now() - ttl))
+        .all(txn)
+        .await?
+        .into_iter()
+        .filter_map(|summoner| summoner.summoner_id.map(|id| (id, summoner.puuid)))
+        .collect())
+}
+
+/// Works out the history entries of looked up summoners: new summoners and changed profile icons or
+/// levels. Summoners that are stored without a history get their stored values recorded as well.
+fn history_entries(
+    summoner_models: &[summoners::ActiveModel],
+    stored: &HashMap<String, summoners::Model>,
+    tracked: &HashSet<String>,
+    now: DateTime<FixedOffset>,
+) -> Vec<summoner_history::ActiveModel> {
+    let entry =
+        |puuid: &str, profile_icon_id, summoner_level, recorded_at| summoner_history::ActiveModel {
+            puuid: Set(puuid.to_string()),
+            profile_icon_id: Set(profile_icon_id),
+            summoner_level: Set(summoner_level),
+            recorded_at: Set(recorded_at),
+            ..Default::default()
+        };
+
+    let mut entries = vec![];
+    for model in summoner_models {
+        let (Set(puuid), Set(profile_icon_id), Set(summoner_level)) =
+            (&model.puuid, &model.profile_icon_id, &model.summoner_level)
+        else {
+            continue;
+        };
+
+        match stored.get(puuid) {
+            Some(old) => {
+                if !tracked.contains(puuid) {
+                    entries.push(entry(
+                        puuid,
+                        old.profile_icon_id,
+                        old.summoner_level,
+                        old.updated_at,
+                    ));
+                }
+                if old.profile_icon_id != *profile_icon_id || old.summoner_level != *summoner_level
+                {
+                    entries.push(entry(puuid, *profile_icon_id, *summoner_level, now));
+                }
+            }
+            None => entries.push(entry(puuid, *profile_icon_id, *summoner_level, now)),
+        }
+    }
+
+    entries
+}
+
+/// Appends the profile icon and level changes of looked up summoners to their history before the
+/// stored summoners are overwritten.
+async fn record_history(
+    summoner_models: &[summoners::ActiveModel],
+    txn: &DatabaseTransaction,
+) -> Result<()> {
+    let puuids: Vec<String> = summoner_models
+        .iter()
+        .filter_map(|model| match &model.puuid {
+            Set(puuid) => Some(puuid.clone()),
+            _ => None,
+        })
+        .collect();
+    if puuids.is_empty() {
+        return Ok(());
+    }
+
+    let stored = summoners::Entity::find()
+        .filter(summoners::Column::Puuid.is_in(puuids.clone()))
+        .all(txn)
+        .await?
+        .into_iter()
+        .map(|model| (model.puuid.clone(), model))
+        .collect();
+    let tracked: Vec<String> = summoner_history::Entity::find()
+        .select_only()
+        .column(summoner_history::Column::Puuid)
+        .distinct()
+        .filter(summoner_history::Column::Puuid.is_in(puuids))
+        .into_tuple()
+        .all(txn)
+        .await?;
+
+    let entries = history_entries(
+        summoner_models,
+        &stored,
+        &tracked.into_iter().collect(),
+        chrono::Utc::now().fixed_offset(),
+    );
+    for chunk in entries.chunks(config::get().database.insert_chunk_size) {
+        summoner_history::Entity::insert_many(chunk.to_vec())
+            .exec(txn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Looks up and stores the summoners that aren't stored or are older than the freshness TTL,
+/// returning the PUUIDs of all summoners that are now stored.
 #[instrument(skip_all, fields(summoners = summoner_ids.len()))]
 pub async fn upsert_summoners(
     summoner_ids: &[&str],
     region: PlatformRoute,
     txn: &DatabaseTransaction,
 ) -> Result<Vec<String>> {
+    let fresh = fresh_summoners(
+        summoner_ids,
+        region,
+        config::get().freshness.summoner_ttl(),
+        txn,
+    )
+    .await?;
+    let summoner_ids: Vec<&str> = summoner_ids
+        .iter()
+        .copied()
+        .filter(|id| !fresh.contains_key(*id))
+        .collect();
+    info!(
+        hits = fresh.len(),
+        misses = summoner_ids.len(),
+        metric = "summoner_cache",
+        "Checked for fresh summoners."
+    );
+    if summoner_ids.is_empty() {
+        return Ok(fresh.into_values().collect());
+    }
+
     let t1 = Instant::now();
     info!("Getting summoner info from league API for summoners...");
 
+    let riot_api = riot_api::for_region(region);
     let results = join_all(summoner_ids.iter().map(|s_id| {
         with_timeout(
-            Duration::from_secs(10),
-            RIOT_API.summoner_v4().get_by_summoner_id(region, s_id),
+            config::get().timeouts.summoner(),
+            riot_api.summoner_v4().get_by_summoner_id(region, s_id),
         )
     }))
     .await;
+    riot_api.record(&results);
 
     info!(
         perf = t1.elapsed().as_millis(),
@@ -49,6 +195,7 @@ pub async fn upsert_summoners(
                 account_id: Set(Some(s.account_id.clone())),
                 profile_icon_id: Set(s.profile_icon_id as i64),
                 summoner_level: Set(s.summoner_level),
+                likely_smurf: Set(s.summoner_level <= config::get().detection.smurf_max_level),
                 ..Default::default()
             }),
             Ok(Err(e)) => {
@@ -62,13 +209,27 @@ pub async fn upsert_summoners(
         })
         .collect();
 
+    if summoner_models.is_empty() && !results.is_empty() {
+        return Err(anyhow!("All summoner API queries failed").context(Dependency::RiotPlatform));
+    }
+
+    record_history(&summoner_models, txn).await?;
+
+    let smurfs = summoner_models
+        .iter()
+        .filter(|model| model.likely_smurf == Set(true))
+        .count();
+    if smurfs > 0 {
+        info!(smurfs, metric = "likely_smurfs", "Found likely smurfs.");
+    }
+
     let t2 = Instant::now();
     info!(
         summoners = summoner_models.len(),
         "Upserting summoners into DB...",
     );
 
-    for chunk in summoner_models.chunks(INSERT_CHUNK_SIZE) {
+    for chunk in summoner_models.chunks(config::get().database.insert_chunk_size) {
         summoners::Entity::insert_many(chunk.to_vec())
             .on_conflict(
                 OnConflict::column(summoners::Column::Puuid)
@@ -80,6 +241,11 @@ pub async fn upsert_summoners(
                         summoners::Column::SummonerLevel,
                         summoners::Column::UpdatedAt,
                     ])
+                    // Once flagged, a summoner stays flagged as it levels up
+                    .value(
+                        summoners::Column::LikelySmurf,
+                        Expr::cust("summoners.likely_smurf OR EXCLUDED.likely_smurf"),
+                    )
                     .to_owned(),
             )
             .exec(txn)
@@ -93,11 +259,105 @@ pub async fn upsert_summoners(
         "Upserted summoners into DB."
     );
 
-    Ok(summoner_models
-        .iter()
-        .filter_map(|s| match s.puuid {
+    Ok(fresh
+        .into_values()
+        .chain(summoner_models.iter().filter_map(|s| match s.puuid {
             Set(ref id) => Some(id.clone()),
             _ => None,
-        })
+        }))
         .collect())
 }
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sea_orm::{DatabaseBackend, MockDatabase, TransactionTrait};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn fresh_summoners_are_not_looked_up() {
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([vec![summoners::Model {
+                summoner_id: Some("summoner1".to_string()),
+                region: PlatformRoute::EUW1.to_string(),
+                account_id: None,
+                profile_icon_id: 1,
+                puuid: "puuid1".to_string(),
+                summoner_level: 30,
+                created_at: Utc::now().into(),
+                updated_at: Utc::now().into(),
+                likely_smurf: false,
+            }]])
+            .into_connection();
+        let txn = db.begin().await.unwrap();
+
+        let puuids = upsert_summoners(&["summoner1"], PlatformRoute::EUW1, &txn)
+            .await
+            .unwrap();
+
+        assert_eq!(puuids, vec!["puuid1".to_string()]);
+    }
+
+    #[test]
+    fn records_new_and_changed_summoners() {
+        let summoner = |puuid: &str, profile_icon_id, summoner_level| summoners::ActiveModel {
+            puuid: Set(puuid.to_string()),
+            profile_icon_id: Set(profile_icon_id),
+            summoner_level: Set(summoner_level),
+            ..Default::default()
+        };
+        let stored = |puuid: &str, profile_icon_id, summoner_level| summoners::Model {
+            summoner_id: None,
+            region: PlatformRoute::EUW1.to_string(),
+            account_id: None,
+            profile_icon_id,
+            puuid: puuid.to_string(),
+            summoner_level,
+            created_at: Utc::now().into(),
+            updated_at: Utc::now().into(),
+            likely_smurf: false,
+        };
+        let stored = HashMap::from([
+            ("leveled".to_string(), stored("leveled", 1, 30)),
+            ("unchanged".to_string(), stored("unchanged", 1, 30)),
+            ("untracked".to_string(), stored("untracked", 1, 30)),
+        ]);
+        let tracked = HashSet::from(["leveled".to_string(), "unchanged".to_string()]);
+
+        let entries = history_entries(
+            &[
+                summoner("new", 1, 30),
+                summoner("leveled", 1, 31),
+                summoner("unchanged", 1, 30),
+                summoner("untracked", 2, 30),
+            ],
+            &stored,
+            &tracked,
+            Utc::now().fixed_offset(),
+        );
+
+        let entries: Vec<(&str, i64, i64)> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.puuid.as_ref().as_str(),
+                    *entry.profile_icon_id.as_ref(),
+                    *entry.summoner_level.as_ref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("new", 1, 30),
+                ("leveled", 1, 31),
+                ("untracked", 1, 30),
+                ("untracked", 2, 30),
+            ]
+        );
+    }
+}
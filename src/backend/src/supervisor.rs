@@ -0,0 +1,156 @@
+use std::any::Any;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use riven::consts::PlatformRoute;
+use tokio::spawn;
+use tokio::task::AbortHandle;
+use tokio::time::sleep;
+use tracing::{error, info, instrument};
+
+use crate::backoff::Backoff;
+use crate::shutdown;
+use crate::status::{self, RegionState};
+
+/// A region task that runs for at least this long is considered to have recovered, so the restart
+/// backoff starts over the next time it fails.
+const STABLE_RUN_TIME: Duration = Duration::from_secs(600);
+
+/// Aborts the region task when the supervisor is dropped, e.g. because it was aborted at shutdown.
+/// Dropping a `JoinHandle` would otherwise leave the region task running.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Runs a region's task in its own tokio task and restarts it with backoff whenever it panics or
+/// returns, so that a failing region never takes the other regions down with it.
+#[instrument(name = "supervise", skip(task))]
+pub async fn supervise<F, Fut>(region: PlatformRoute, task: F)
+where
+    F: Fn(PlatformRoute) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(300));
+
+    loop {
+        let started_at = Instant::now();
+        let handle = spawn(task(region));
+        let _abort = AbortOnDrop(handle.abort_handle());
+        let failure = match handle.await {
+            Ok(Ok(())) => "region task exited".to_string(),
+            Ok(Err(error)) => format!("{:?}", error),
+            Err(join_error) if join_error.is_panic() => {
+                format!("panic: {}", panic_message(&*join_error.into_panic()))
+            }
+            Err(join_error) => format!("{:?}", join_error),
+        };
+
+        if shutdown::is_requested() {
+            info!("Region task stopped.");
+            return;
+        }
+
+        if started_at.elapsed() >= STABLE_RUN_TIME {
+            backoff.reset();
+        }
+
+        let restarts = status::record_restart(region);
+        let delay = backoff.next_delay();
+        status::set_state(region, RegionState::Degraded("restarting".to_string()));
+        error!(
+            failure,
+            restarts,
+            delay_ms = delay.as_millis(),
+            metric = "region_restart",
+            "Region task failed, restarting."
+        );
+
+        tokio::select! {
+            _ = sleep(delay) => info!("Restarting region task..."),
+            _ = shutdown::requested() => {
+                info!("Shutdown requested, not restarting region task.");
+                return;
+            }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_panicking_task() {
+        let runs = Arc::new(AtomicU32::new(0));
+
+        let task_runs = runs.clone();
+        let supervised = supervise(PlatformRoute::OC1, move |_| {
+            let runs = task_runs.clone();
+            async move {
+                if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("first run panics");
+                }
+                Err(anyhow!("second run fails"))
+            }
+        });
+        let _ = tokio::time::timeout(Duration::from_secs(60), supervised).await;
+
+        assert!(runs.load(Ordering::SeqCst) >= 3);
+    }
+
+    #[tokio::test]
+    async fn aborting_the_supervisor_aborts_the_task() {
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let (dropped_tx, dropped_rx) = tokio::sync::oneshot::channel::<()>();
+        let started_tx = std::sync::Mutex::new(Some(started_tx));
+        let dropped_tx = std::sync::Mutex::new(Some(dropped_tx));
+
+        let supervisor = spawn(supervise(PlatformRoute::OC1, move |_| {
+            let started = started_tx.lock().unwrap().take();
+            let dropped = dropped_tx.lock().unwrap().take();
+            async move {
+                // Dropped, closing the channel, once the task is aborted
+                let _dropped = dropped;
+                if let Some(started) = started {
+                    let _ = started.send(());
+                }
+                std::future::pending::<()>().await;
+                Ok(())
+            }
+        }));
+        started_rx.await.unwrap();
+        supervisor.abort();
+
+        assert!(dropped_rx.await.is_err());
+    }
+
+    #[test]
+    fn extracts_panic_messages() {
+        assert_eq!(panic_message(&"static message"), "static message");
+        assert_eq!(panic_message(&"owned message".to_string()), "owned message");
+        assert_eq!(panic_message(&42), "unknown panic");
+    }
+}
@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use riven::consts::PlatformRoute;
+use sd_notify::NotifyState;
+use tokio::time::interval;
+use tracing::{info, instrument, warn};
+
+use crate::config;
+use crate::status::{self, RegionState, RegionStatus};
+
+fn notify(state: &[NotifyState]) {
+    if let Err(error) = sd_notify::notify(false, state) {
+        warn!(?error, "Failed to notify systemd.");
+    }
+}
+
+/// Tells systemd that the backend has started.
+pub fn ready() {
+    info!("Notifying systemd that the backend is ready.");
+    notify(&[NotifyState::Ready]);
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// A region is live if it has committed a cycle recently. Regions that haven't committed their
+/// first cycle yet get the same grace period counted from startup. Regions waiting for a new API
+/// key are live too, since restarting the backend wouldn't fix the key.
+fn is_live(status: &RegionStatus, started_at: Instant) -> bool {
+    status.state == RegionState::ApiKeyInvalid
+        || status.last_cycle.unwrap_or(started_at).elapsed()
+            < config::get().service.watchdog_stale_after()
+}
+
+/// Regions that aren't live. A region that hasn't reported a status yet is still starting, so it
+/// gets the startup grace period too.
+fn stale_regions(
+    regions: &[PlatformRoute],
+    statuses: &[(PlatformRoute, RegionStatus)],
+    started_at: Instant,
+) -> Vec<String> {
+    regions
+        .iter()
+        .filter(|region| {
+            let status = statuses
+                .iter()
+                .find(|(r, _)| r == *region)
+                .map(|(_, status)| status.clone())
+                .unwrap_or_default();
+            !is_live(&status, started_at)
+        })
+        .map(|region| region.to_string())
+        .collect()
+}
+
+/// One line summary of every region's state for `systemctl status`.
+fn summary(statuses: &[(PlatformRoute, RegionStatus)]) -> String {
+    statuses
+        .iter()
+        .map(|(region, status)| {
+            let last_cycle = match status.last_cycle {
+                Some(at) => format!("{}s ago", at.elapsed().as_secs()),
+                None => "never".to_string(),
+            };
+            format!(
+                "{}: {}, last cycle {}, {} restarts",
+                region, status.state, last_cycle, status.restarts
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// Periodically updates the service status and pings the systemd watchdog while every region is
+/// live. Does nothing beyond status updates if the watchdog isn't enabled for the service.
+#[instrument(name = "watchdog", skip_all)]
+pub async fn run_watchdog(regions: Vec<PlatformRoute>) {
+    let started_at = Instant::now();
+
+    let mut watchdog_usec = 0;
+    let watchdog_enabled = sd_notify::watchdog_enabled(false, &mut watchdog_usec);
+    // Ping twice per watchdog period so that a single late tick doesn't trigger it
+    let period = if watchdog_enabled {
+        Duration::from_micros(watchdog_usec / 2)
+    } else {
+        Duration::from_secs(30)
+    };
+    info!(
+        watchdog_enabled,
+        period_ms = period.as_millis(),
+        "Starting watchdog..."
+    );
+
+    let mut ticks = interval(period);
+    loop {
+        ticks.tick().await;
+
+        let statuses = status::all();
+        notify(&[NotifyState::Status(&summary(&statuses))]);
+
+        let stale = stale_regions(&regions, &statuses, started_at);
+
+        if !watchdog_enabled {
+            continue;
+        }
+        if stale.is_empty() {
+            notify(&[NotifyState::Watchdog]);
+        } else {
+            warn!(
+                ?stale,
+                "Regions have not completed a cycle recently, withholding watchdog ping."
+            );
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------------------------------------------------------------------
+// ----------------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(last_cycle: Option<Instant>) -> RegionStatus {
+        RegionStatus {
+            state: RegionState::Running,
+            restarts: 0,
+            last_cycle,
+        }
+    }
+
+    #[test]
+    fn recent_cycle_is_live() {
+        let long_ago = Instant::now() - config::get().service.watchdog_stale_after() * 2;
+        assert!(is_live(&status(Some(Instant::now())), long_ago));
+        assert!(!is_live(&status(Some(long_ago)), long_ago));
+    }
+
+    #[test]
+    fn waiting_for_api_key_is_live() {
+        let long_ago = Instant::now() - config::get().service.watchdog_stale_after() * 2;
+        let mut waiting = status(Some(long_ago));
+        waiting.state = RegionState::ApiKeyInvalid;
+        assert!(is_live(&waiting, long_ago));
+    }
+
+    #[test]
+    fn first_cycle_gets_grace_period() {
+        let long_ago = Instant::now() - config::get().service.watchdog_stale_after() * 2;
+        assert!(is_live(&status(None), Instant::now()));
+        assert!(!is_live(&status(None), long_ago));
+    }
+    #[test]
+    fn regions_without_status_get_grace_period() {
+        let long_ago = Instant::now() - config::get().service.watchdog_stale_after() * 2;
+        let regions = [PlatformRoute::EUW1, PlatformRoute::KR];
+        let statuses = [(PlatformRoute::EUW1, status(Some(Instant::now())))];
+        assert!(stale_regions(&regions, &statuses, Instant::now()).is_empty());
+        assert_eq!(stale_regions(&regions, &statuses, long_ago), vec!["KR"]);
+    }
+}
@@ -5,8 +5,7 @@ use futures::Future;
 
 pub async fn with_timeout<F, T>(timeout: Duration, future: F) -> Result<T>
 where
-    F: Future<Output = T> + Send + 'static,
-    T: Send + 'static,
+    F: Future<Output = T>,
 {
     match tokio::time::timeout(timeout, future).await {
         Ok(result) => Ok(result),
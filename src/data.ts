@@ -3,6 +3,8 @@ import {
   dodges,
   lolPros,
   playerCounts,
+  positionEnum,
+  proPlayers,
   riotIds,
   summoners,
 } from "@/src/db/schema";
@@ -10,9 +12,18 @@ import "dotenv/config";
 import { and, asc, desc, eq, gt, lt, sql } from "drizzle-orm";
 import { db } from "./db";
 import type { Dodge, Tier } from "./lib/types"; // Assuming Dodge is properly defined to match the query results
+import { searchName } from "./lib/utils";
 import { userRegionToRiotRegion } from "./regions";
 import { seasons } from "./seasons";
 
+// Pros tagged in pro_players, e.g. from the curated file, take precedence over the lolpros
+// profiles linked by slug
+const proPlayerFields = {
+  lolProsName: sql<string | null>`COALESCE(${proPlayers.name}, ${lolPros.name})`,
+  lolProsCountry: sql<string | null>`COALESCE(${proPlayers.country}, ${lolPros.country})`,
+  lolProsPosition: sql<(typeof positionEnum.enumValues)[number] | null>`COALESCE(${proPlayers.position}, ${lolPros.position})`,
+};
+
 export async function getDodges(
   riotRegion: string,
   pageSize: number,
@@ -24,9 +35,7 @@ export async function getDodges(
       gameName: riotIds.gameName,
       tagLine: riotIds.tagLine,
       lolProsSlug: riotIds.lolprosSlug,
-      lolProsName: lolPros.name,
-      lolProsCountry: lolPros.country,
-      lolProsPosition: lolPros.position,
+      ...proPlayerFields,
       profileIconId: summoners.profileIconId,
       riotRegion: dodges.region,
       rankTier: dodges.rankTier,
@@ -44,6 +53,7 @@ export async function getDodges(
     )
     .innerJoin(riotIds, eq(summoners.puuid, riotIds.puuid))
     .leftJoin(lolPros, eq(riotIds.lolprosSlug, lolPros.slug))
+    .leftJoin(proPlayers, eq(riotIds.puuid, proPlayers.puuid))
     .where(eq(dodges.region, riotRegion))
     .orderBy(desc(dodges.createdAt), desc(dodges.dodgeId))
     .limit(pageSize)
@@ -62,9 +72,7 @@ export async function getDodgesByPlayer(
       gameName: riotIds.gameName,
       tagLine: riotIds.tagLine,
       lolProsSlug: riotIds.lolprosSlug,
-      lolProsName: lolPros.name,
-      lolProsCountry: lolPros.country,
-      lolProsPosition: lolPros.position,
+      ...proPlayerFields,
       profileIconId: summoners.profileIconId,
       riotRegion: dodges.region,
       rankTier: dodges.rankTier,
@@ -82,10 +90,11 @@ export async function getDodgesByPlayer(
     )
     .innerJoin(riotIds, eq(summoners.puuid, riotIds.puuid))
     .leftJoin(lolPros, eq(riotIds.lolprosSlug, lolPros.slug))
+    .leftJoin(proPlayers, eq(riotIds.puuid, proPlayers.puuid))
     .where(
       and(
-        sql<boolean>`${riotIds.lowerGameName} = LOWER(${gameName})`,
-        sql<boolean>`${riotIds.lowerTagLine} = LOWER(${tagLine})`,
+        eq(riotIds.lowerGameName, searchName(gameName)),
+        eq(riotIds.lowerTagLine, searchName(tagLine)),
       ),
     )
     .orderBy(desc(dodges.createdAt))
@@ -118,8 +127,8 @@ export async function getAllDodgesByPlayer(gameName: string, tagLine: string) {
     .innerJoin(riotIds, eq(summoners.puuid, riotIds.puuid))
     .where(
       and(
-        sql<boolean>`${riotIds.lowerGameName} = LOWER(${gameName})`,
-        sql<boolean>`${riotIds.lowerTagLine} = LOWER(${tagLine})`,
+        eq(riotIds.lowerGameName, searchName(gameName)),
+        eq(riotIds.lowerTagLine, searchName(tagLine)),
       ),
     )
     .orderBy(desc(dodges.createdAt));
@@ -149,9 +158,7 @@ export async function getSummoner(
       tagLine: riotIds.tagLine,
       riotRegion: summoners.region,
       lolProsSlug: riotIds.lolprosSlug,
-      lolProsName: lolPros.name,
-      lolProsCountry: lolPros.country,
-      lolProsPosition: lolPros.position,
+      ...proPlayerFields,
       summonerLevel: summoners.summonerLevel,
       profileIconId: summoners.profileIconId,
       rankTier: apexTierPlayers.rankTier,
@@ -170,10 +177,11 @@ export async function getSummoner(
       ),
     )
     .leftJoin(lolPros, eq(riotIds.lolprosSlug, lolPros.slug))
+    .leftJoin(proPlayers, eq(riotIds.puuid, proPlayers.puuid))
     .where(
       and(
-        sql<boolean>`${riotIds.lowerGameName} = LOWER(${gameName})`,
-        sql<boolean>`${riotIds.lowerTagLine} = LOWER(${tagLine})`,
+        eq(riotIds.lowerGameName, searchName(gameName)),
+        eq(riotIds.lowerTagLine, searchName(tagLine)),
       ),
     )
     .limit(1);
@@ -196,8 +204,8 @@ export async function getDodgeCounts(gameName: string, tagLine: string) {
     .innerJoin(dodges, eq(summoners.summonerId, dodges.summonerId))
     .where(
       and(
-        sql<boolean>`${riotIds.lowerGameName} = LOWER(${gameName})`,
-        sql<boolean>`${riotIds.lowerTagLine} = LOWER(${tagLine})`,
+        eq(riotIds.lowerGameName, searchName(gameName)),
+        eq(riotIds.lowerTagLine, searchName(tagLine)),
       ),
     )
     .having(sql<number>`COUNT(${dodges.dodgeId}) > 0`);
@@ -269,9 +277,7 @@ export async function getLeaderboard(
       tagLine: riotIds.tagLine,
       riotRegion: summoners.region,
       lolProsSlug: riotIds.lolprosSlug,
-      lolProsName: lolPros.name,
-      lolProsCountry: lolPros.country,
-      lolProsPosition: lolPros.position,
+      ...proPlayerFields,
       rankTier: apexTierPlayers.rankTier,
       currentLP: apexTierPlayers.currentLp,
       profileIconId: summoners.profileIconId,
@@ -296,6 +302,7 @@ export async function getLeaderboard(
       ),
     )
     .leftJoin(lolPros, eq(riotIds.lolprosSlug, lolPros.slug))
+    .leftJoin(proPlayers, eq(riotIds.puuid, proPlayers.puuid))
     .groupBy(
       riotIds.gameName,
       riotIds.tagLine,
@@ -304,6 +311,9 @@ export async function getLeaderboard(
       lolPros.name,
       lolPros.country,
       lolPros.position,
+      proPlayers.name,
+      proPlayers.country,
+      proPlayers.position,
       apexTierPlayers.rankTier,
       apexTierPlayers.currentLp,
       summoners.profileIconId,
@@ -366,8 +376,8 @@ export async function getDodgesCount(
       .where(
         and(
           eq(dodges.region, riotRegion),
-          sql<boolean>`${riotIds.lowerGameName} = LOWER(${gameName})`,
-          sql<boolean>`${riotIds.lowerTagLine} = LOWER(${tagLine})`,
+          eq(riotIds.lowerGameName, searchName(gameName)),
+          eq(riotIds.lowerTagLine, searchName(tagLine)),
         ),
       );
   } else {
@@ -1,18 +1,14 @@
-CREATE OR REPLACE FUNCTION notify_dodge_insert() RETURNS trigger AS $$
-DECLARE
-    dodge_data json;
-BEGIN
+CREATE OR REPLACE FUNCTION dodge_notification(bigint) RETURNS json AS $$
     SELECT row_to_json(t)
-    INTO dodge_data
     FROM (
         SELECT 
             d.dodge_id AS "dodgeId", 
             r.game_name AS "gameName", 
             r.tag_line AS "tagLine", 
             r.lolpros_slug AS "lolProsSlug", 
-            l.name AS "lolProsName", 
-            l.country AS "lolProsCountry", 
-            l.position AS "lolProsPosition", 
+            COALESCE(p.name, l.name) AS "lolProsName", 
+            COALESCE(p.country, l.country) AS "lolProsCountry", 
+            COALESCE(p.position, l.position) AS "lolProsPosition", 
             s.profile_icon_id AS "profileIconId", 
             d.region AS "riotRegion", 
             d.rank_tier AS "rankTier", 
@@ -23,10 +19,23 @@ BEGIN
         JOIN summoners s ON d.summoner_id = s.summoner_id AND d.region = s.region
         JOIN riot_ids r ON s.puuid = r.puuid
         LEFT JOIN lol_pros l ON r.lolpros_slug = l.slug
-        WHERE NEW.dodge_id = d.dodge_id
+        -- Pros tagged in pro_players, e.g. from the curated file, take precedence over lolpros
+        LEFT JOIN pro_players p ON r.puuid = p.puuid
+        WHERE d.dodge_id = $1
     ) t;
+$$ LANGUAGE sql STABLE;
+
+CREATE OR REPLACE FUNCTION notify_dodge_insert() RETURNS trigger AS $$
+DECLARE
+    dodge_data json;
+BEGIN
+    dodge_data := dodge_notification(NEW.dodge_id);
 
-    PERFORM pg_notify('dodge_insert', dodge_data::text);
+    -- Dodges that could not be enriched yet are announced by the enrichment job worker once their
+    -- lookups succeed
+    IF dodge_data IS NOT NULL THEN
+        PERFORM pg_notify('dodge_insert', dodge_data::text);
+    END IF;
     RETURN NEW;
 END;
 $$ LANGUAGE plpgsql;
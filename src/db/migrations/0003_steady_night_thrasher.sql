@@ -0,0 +1,21 @@
+DO $$ BEGIN
+ CREATE TYPE "dodgetracker"."enrichment_job_status_enum" AS ENUM('PENDING', 'DONE', 'DEAD');
+EXCEPTION
+ WHEN duplicate_object THEN null;
+END $$;
+--> statement-breakpoint
+CREATE TABLE IF NOT EXISTS "dodgetracker"."enrichment_jobs" (
+	"job_id" bigserial PRIMARY KEY NOT NULL,
+	"dodge_id" bigint NOT NULL,
+	"summoner_id" varchar(255) NOT NULL,
+	"region" varchar(10) NOT NULL,
+	"status" "dodgetracker"."enrichment_job_status_enum" DEFAULT 'PENDING' NOT NULL,
+	"attempts" integer DEFAULT 0 NOT NULL,
+	"next_attempt_at" timestamp with time zone DEFAULT now() NOT NULL,
+	"last_error" text,
+	"created_at" timestamp with time zone DEFAULT now() NOT NULL,
+	"updated_at" timestamp with time zone DEFAULT now() NOT NULL
+);
+--> statement-breakpoint
+CREATE UNIQUE INDEX IF NOT EXISTS "enrichment_jobs_dodge_id" ON "dodgetracker"."enrichment_jobs" USING btree (dodge_id int8_ops);--> statement-breakpoint
+CREATE INDEX IF NOT EXISTS "enrichment_jobs_status_next_attempt_at" ON "dodgetracker"."enrichment_jobs" USING btree (status enum_ops,next_attempt_at timestamptz_ops);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS "dodgetracker"."riot_id_history" (
+	"id" bigserial PRIMARY KEY NOT NULL,
+	"puuid" varchar(255) NOT NULL,
+	"game_name" varchar(255) NOT NULL,
+	"tag_line" varchar(255) NOT NULL,
+	"valid_from" timestamp with time zone DEFAULT now() NOT NULL,
+	"valid_to" timestamp with time zone
+);
+--> statement-breakpoint
+CREATE INDEX IF NOT EXISTS "riot_id_history_puuid_valid_to" ON "dodgetracker"."riot_id_history" USING btree (puuid text_ops,valid_to timestamptz_ops);--> statement-breakpoint
+CREATE INDEX IF NOT EXISTS "riot_id_history_game_name_tag_line" ON "dodgetracker"."riot_id_history" USING btree (game_name text_ops,tag_line text_ops);
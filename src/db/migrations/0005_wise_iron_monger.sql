@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS "dodgetracker"."summoner_history" (
+	"id" bigserial PRIMARY KEY NOT NULL,
+	"puuid" varchar(255) NOT NULL,
+	"profile_icon_id" bigint NOT NULL,
+	"summoner_level" bigint NOT NULL,
+	"recorded_at" timestamp with time zone DEFAULT now() NOT NULL
+);
+--> statement-breakpoint
+ALTER TABLE "dodgetracker"."summoners" ADD COLUMN "likely_smurf" boolean DEFAULT false NOT NULL;--> statement-breakpoint
+CREATE INDEX IF NOT EXISTS "summoner_history_puuid_recorded_at" ON "dodgetracker"."summoner_history" USING btree (puuid text_ops,recorded_at timestamptz_ops);
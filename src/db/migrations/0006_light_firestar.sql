@@ -0,0 +1,2 @@
+ALTER TABLE "dodgetracker"."lol_pros" ALTER COLUMN "country" DROP NOT NULL;--> statement-breakpoint
+ALTER TABLE "dodgetracker"."lol_pros" ALTER COLUMN "position" DROP NOT NULL;
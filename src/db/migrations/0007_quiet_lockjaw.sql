@@ -0,0 +1 @@
+ALTER TABLE "dodgetracker"."riot_ids" ADD COLUMN "lolpros_checked_at" timestamp with time zone;
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS "dodgetracker"."pro_players" (
+	"puuid" varchar(255) PRIMARY KEY NOT NULL,
+	"source" varchar(32) NOT NULL,
+	"name" varchar(255) NOT NULL,
+	"team" varchar(255),
+	"country" varchar(5),
+	"position" "dodgetracker"."position_enum",
+	"created_at" timestamp with time zone DEFAULT now() NOT NULL,
+	"updated_at" timestamp with time zone DEFAULT now() NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE "dodgetracker"."apex_tier_players" ADD COLUMN "refresh_attempted_at" timestamp with time zone;
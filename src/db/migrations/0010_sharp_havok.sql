@@ -0,0 +1,4 @@
+ALTER TABLE "dodgetracker"."riot_id_history" ADD COLUMN "lower_game_name" varchar(255);--> statement-breakpoint
+ALTER TABLE "dodgetracker"."riot_id_history" ADD COLUMN "lower_tag_line" varchar(255);--> statement-breakpoint
+DROP INDEX IF EXISTS "dodgetracker"."riot_id_history_game_name_tag_line";--> statement-breakpoint
+CREATE INDEX IF NOT EXISTS "riot_id_history_lower_game_name_tag_line" ON "dodgetracker"."riot_id_history" USING btree (lower_game_name text_ops,lower_tag_line text_ops);
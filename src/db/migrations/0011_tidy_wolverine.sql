@@ -0,0 +1 @@
+ALTER TABLE "dodgetracker"."riot_ids" ADD COLUMN "lolpros_synced" boolean DEFAULT false NOT NULL;
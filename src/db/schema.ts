@@ -1,9 +1,12 @@
 import {
   bigint,
   bigserial,
+  boolean,
   index,
+  integer,
   pgSchema,
   primaryKey,
+  text,
   timestamp,
   uniqueIndex,
   varchar,
@@ -93,6 +96,8 @@ export const summoners = dodgetracker.table(
     updatedAt: timestamp("updated_at", { withTimezone: true, mode: "date" })
       .defaultNow()
       .notNull(),
+    // Seen on the apex ladder at a low summoner level
+    likelySmurf: boolean("likely_smurf").default(false).notNull(),
   },
   (table) => {
     return {
@@ -125,8 +130,15 @@ export const riotIds = dodgetracker.table(
       .defaultNow()
       .notNull(),
     lolprosSlug: varchar("lolpros_slug", { length: 255 }),
+    // When lolpros was last searched for this Riot ID, whether or not a profile was found
+    lolprosCheckedAt: timestamp("lolpros_checked_at", {
+      withTimezone: true,
+      mode: "date",
+    }),
     lowerGameName: varchar("lower_game_name", { length: 255 }),
     lowerTagLine: varchar("lower_tag_line", { length: 255 }),
+    // Whether the slug was linked by the lolpros ladder sync rather than found by a search
+    lolprosSynced: boolean("lolpros_synced").default(false).notNull(),
   },
   (table) => {
     return {
@@ -215,6 +227,11 @@ export const apexTierPlayers = dodgetracker.table(
     // You can use { mode: "bigint" } if numbers are exceeding js number limitations
     losses: bigint("losses", { mode: "number" }).notNull(),
     rankTier: rankTierEnum("rank_tier").notNull(),
+    // When the background refresh last looked the player up, whether or not it succeeded
+    refreshAttemptedAt: timestamp("refresh_attempted_at", {
+      withTimezone: true,
+      mode: "date",
+    }),
   },
   (table) => {
     return {
@@ -249,8 +266,9 @@ export const positionEnum = dodgetracker.enum("position_enum", [
 export const lolPros = dodgetracker.table("lol_pros", {
   slug: varchar("slug", { length: 255 }).primaryKey().notNull(),
   name: varchar("name", { length: 255 }).notNull(),
-  country: varchar("country", { length: 5 }).notNull(),
-  position: positionEnum("position").notNull(),
+  // Not every lolpros profile has a country or a known position
+  country: varchar("country", { length: 5 }),
+  position: positionEnum("position"),
   createdAt: timestamp("created_at", { withTimezone: true, mode: "date" })
     .defaultNow()
     .notNull(),
@@ -258,3 +276,115 @@ export const lolPros = dodgetracker.table("lol_pros", {
     .defaultNow()
     .notNull(),
 });
+
+// Pros from sources other than lolpros, e.g. the curated pro player file, for any region
+export const proPlayers = dodgetracker.table("pro_players", {
+  puuid: varchar("puuid", { length: 255 }).primaryKey().notNull(),
+  source: varchar("source", { length: 32 }).notNull(),
+  name: varchar("name", { length: 255 }).notNull(),
+  team: varchar("team", { length: 255 }),
+  country: varchar("country", { length: 5 }),
+  position: positionEnum("position"),
+  createdAt: timestamp("created_at", { withTimezone: true, mode: "date" })
+    .defaultNow()
+    .notNull(),
+  updatedAt: timestamp("updated_at", { withTimezone: true, mode: "date" })
+    .defaultNow()
+    .notNull(),
+});
+
+export const enrichmentJobStatusEnum = dodgetracker.enum(
+  "enrichment_job_status_enum",
+  ["PENDING", "DONE", "DEAD"],
+);
+
+export const enrichmentJobs = dodgetracker.table(
+  "enrichment_jobs",
+  {
+    jobId: bigserial("job_id", { mode: "bigint" }).primaryKey().notNull(),
+    dodgeId: bigint("dodge_id", { mode: "bigint" }).notNull(),
+    summonerId: varchar("summoner_id", { length: 255 }).notNull(),
+    region: varchar("region", { length: 10 }).notNull(),
+    status: enrichmentJobStatusEnum("status").default("PENDING").notNull(),
+    attempts: integer("attempts").default(0).notNull(),
+    nextAttemptAt: timestamp("next_attempt_at", {
+      withTimezone: true,
+      mode: "date",
+    })
+      .defaultNow()
+      .notNull(),
+    lastError: text("last_error"),
+    createdAt: timestamp("created_at", { withTimezone: true, mode: "date" })
+      .defaultNow()
+      .notNull(),
+    updatedAt: timestamp("updated_at", { withTimezone: true, mode: "date" })
+      .defaultNow()
+      .notNull(),
+  },
+  (table) => {
+    return {
+      dodgeId: uniqueIndex("enrichment_jobs_dodge_id").using(
+        "btree",
+        table.dodgeId,
+      ),
+      statusNextAttemptAt: index("enrichment_jobs_status_next_attempt_at").using(
+        "btree",
+        table.status,
+        table.nextAttemptAt,
+      ),
+    };
+  },
+);
+
+export const riotIdHistory = dodgetracker.table(
+  "riot_id_history",
+  {
+    id: bigserial("id", { mode: "bigint" }).primaryKey().notNull(),
+    puuid: varchar("puuid", { length: 255 }).notNull(),
+    gameName: varchar("game_name", { length: 255 }).notNull(),
+    tagLine: varchar("tag_line", { length: 255 }).notNull(),
+    validFrom: timestamp("valid_from", { withTimezone: true, mode: "date" })
+      .defaultNow()
+      .notNull(),
+    // Null while the Riot ID is current
+    validTo: timestamp("valid_to", { withTimezone: true, mode: "date" }),
+    lowerGameName: varchar("lower_game_name", { length: 255 }),
+    lowerTagLine: varchar("lower_tag_line", { length: 255 }),
+  },
+  (table) => {
+    return {
+      puuidValidTo: index("riot_id_history_puuid_valid_to").using(
+        "btree",
+        table.puuid,
+        table.validTo,
+      ),
+      lowerGameNameTagLine: index(
+        "riot_id_history_lower_game_name_tag_line",
+      ).using("btree", table.lowerGameName, table.lowerTagLine),
+    };
+  },
+);
+
+export const summonerHistory = dodgetracker.table(
+  "summoner_history",
+  {
+    id: bigserial("id", { mode: "bigint" }).primaryKey().notNull(),
+    puuid: varchar("puuid", { length: 255 }).notNull(),
+    // You can use { mode: "bigint" } if numbers are exceeding js number limitations
+    profileIconId: bigint("profile_icon_id", { mode: "number" }).notNull(),
+    // You can use { mode: "bigint" } if numbers are exceeding js number limitations
+    summonerLevel: bigint("summoner_level", { mode: "number" }).notNull(),
+    recordedAt: timestamp("recorded_at", { withTimezone: true, mode: "date" })
+      .defaultNow()
+      .notNull(),
+  },
+  (table) => {
+    return {
+      puuidRecordedAt: index("summoner_history_puuid_recorded_at").using(
+        "btree",
+        table.puuid,
+        table.recordedAt,
+      ),
+    };
+  },
+);
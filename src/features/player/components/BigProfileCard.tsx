@@ -29,7 +29,6 @@ export default async function BigProfileCard(props: {
   const recentlyUpdated = isWithinDays(props.lastUpdateTime, new Date(), 3);
 
   const hasLolProsInfo =
-    props.lolProsSlug &&
     props.lolProsName &&
     props.lolProsPosition &&
     props.lolProsCountry;
@@ -71,3 +71,9 @@ export function profileIconUrl(profileIconID: number): string {
 export function secondsBetween(from: Date, to: Date): number {
   return Math.abs(from.getTime() - to.getTime()) / 1000;
 }
+
+// Normalises a game name or tag line the way the backend stores it in lower_game_name and
+// lower_tag_line, so that searches match names with accents in either encoding
+export function searchName(name: string): string {
+  return name.normalize("NFC").toLowerCase();
+}